@@ -0,0 +1,88 @@
+//! Compares evaluating positions one by one with `NeuralNet::run`
+//! against evaluating them all at once with `NeuralNet::run_batch`.
+//! Needs the model at `constants::model::NET_PATH`, run with `cargo bench`
+use std::time::{Duration, Instant};
+
+use lib::constants;
+use lib::net::tensorflow::NeuralNet;
+use lib::net::NetError;
+use lib::rules::types::GameState;
+
+const BATCH_SIZES: [usize; 4] = [1, 8, 32, 128];
+const NUM_ROUNDS: usize = 10;
+
+/// Positions from random games, `i` moves into the game
+fn random_positions(n: usize) -> Vec<GameState> {
+    (0..n)
+        .map(|i| {
+            let mut game_state = GameState::init_game_state();
+            for _ in 0..i % 40 {
+                if game_state.evaluate().has_ended() {
+                    break;
+                }
+                game_state.move_game_randomly();
+            }
+            game_state
+        })
+        .collect()
+}
+
+fn per_position(d: Duration, n: usize) -> f64 {
+    d.as_secs_f64() * 1e6 / (n * NUM_ROUNDS) as f64
+}
+
+fn main() -> Result<(), NetError> {
+    let net = match NeuralNet::load(constants::model::NET_PATH) {
+        Ok(net) => net,
+        Err(e) => {
+            eprintln!("{}, skipping benchmark", e);
+            return Ok(());
+        }
+    };
+
+    // warm up
+    let warm_up = random_positions(8);
+    net.run_batch(&warm_up)?;
+    for game_state in warm_up.iter() {
+        net.run(game_state)?;
+    }
+
+    for batch_size in BATCH_SIZES {
+        let positions = random_positions(batch_size);
+
+        let start = Instant::now();
+        for _ in 0..NUM_ROUNDS {
+            for game_state in positions.iter() {
+                net.run(game_state)?;
+            }
+        }
+        let single = start.elapsed();
+
+        let start = Instant::now();
+        for _ in 0..NUM_ROUNDS {
+            net.run_batch(&positions)?;
+        }
+        let batched = start.elapsed();
+
+        // both paths have to agree
+        let outputs = net.run_batch(&positions)?;
+        for (game_state, batched_output) in positions.iter().zip(outputs.iter()) {
+            let output = net.run(game_state)?;
+            assert!((output.value_head - batched_output.value_head).abs() < 1e-4);
+            assert!(output
+                .policy_head
+                .iter()
+                .zip(batched_output.policy_head.iter())
+                .all(|(a, b)| (a - b).abs() < 1e-4));
+        }
+
+        println!(
+            "batch {:4}: run {:8.1} us/position, run_batch {:8.1} us/position ({:.1}x)",
+            batch_size,
+            per_position(single, batch_size),
+            per_position(batched, batch_size),
+            single.as_secs_f64() / batched.as_secs_f64()
+        );
+    }
+    Ok(())
+}
@@ -2,5 +2,5 @@ call conda activate base
 for /l %%x in (1, 1, 100) do (
     echo ============== %%x
     cargo run
-    python scripts/model_trainer.py
+    cargo run -- train
 )
\ No newline at end of file
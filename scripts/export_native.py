@@ -0,0 +1,77 @@
+"""Script used for exporting the weights of the model at NET_PATH to NATIVE_PATH, for the native Rust network (src/net/native.rs).
+`cargo test -- --ignored matches_tensorflow` then checks the Rust network against the model"""
+
+
+import json
+import os
+import re
+
+import numpy as np
+from tensorflow.keras import layers
+
+from load_and_save_model import load
+
+
+def inbound_layer(layer):
+    """The layer before `layer`, for an Add it's the residual branch (the skip branch joins it further back)"""
+    inbound = layer.inbound_nodes[0].inbound_layers
+    if isinstance(inbound, list):
+        return inbound[-1]
+    return inbound
+
+
+def weighted_layers(output_layer):
+    """Conv2D, BatchNormalization and Dense layers from the input to `output_layer`, in order"""
+    res = []
+    layer = output_layer
+    while not isinstance(layer, layers.InputLayer):
+        if isinstance(layer, (layers.Conv2D, layers.BatchNormalization, layers.Dense)):
+            res.append(layer)
+        layer = inbound_layer(layer)
+    return res[::-1]
+
+
+def save(path, name, array):
+    np.save(os.path.join(path, name + ".npy"), np.asarray(array, dtype=np.float32))
+
+
+def save_conv_bn(path, conv_name, bn_name, conv, bn):
+    assert isinstance(conv, layers.Conv2D) and isinstance(bn, layers.BatchNormalization)
+    save(path, conv_name, conv.get_weights()[0])
+    gamma, beta, mean, var = bn.get_weights()
+    scale = gamma / np.sqrt(var + bn.epsilon)
+    save(path, bn_name + "_scale", scale)
+    save(path, bn_name + "_shift", beta - mean * scale)
+
+
+if __name__ == "__main__":
+    with open("constants.jsonc", "r") as f:
+        constants = json.loads(re.sub("//.*", "", f.read(), flags=re.MULTILINE))
+
+    model = load(constants["NET_PATH"])
+    path = constants["NATIVE_PATH"]
+    os.makedirs(path, exist_ok=True)
+
+    value = weighted_layers(model.get_layer("value_head"))
+    policy = weighted_layers(model.get_layer("policy_head"))
+    # the tower is shared by both heads
+    tower_len = 0
+    while value[tower_len] is policy[tower_len]:
+        tower_len += 1
+    tower, value, policy = value[:tower_len], value[tower_len:], policy[tower_len:]
+
+    save_conv_bn(path, "stem_conv", "stem_bn", tower[0], tower[1])
+    for i, start in enumerate(range(2, len(tower), 4)):
+        conv1, bn1, conv2, bn2 = tower[start : start + 4]
+        save_conv_bn(path, f"res{i}_conv1", f"res{i}_bn1", conv1, bn1)
+        save_conv_bn(path, f"res{i}_conv2", f"res{i}_bn2", conv2, bn2)
+
+    value_conv, value_bn, value_dense1, value_dense2 = value
+    save_conv_bn(path, "value_conv", "value_bn", value_conv, value_bn)
+    save(path, "value_dense1", value_dense1.get_weights()[0])
+    save(path, "value_dense2", value_dense2.get_weights()[0])
+
+    policy_conv, policy_bn, policy_head = policy
+    save_conv_bn(path, "policy_conv", "policy_bn", policy_conv, policy_bn)
+    save(path, "policy_head", policy_head.get_weights()[0])
+    print("Exported", constants["NET_PATH"], "to", path)
@@ -0,0 +1,30 @@
+"""Script used for exporting the model at NET_PATH to ONNX_PATH, for the `onnx` feature of the crate.
+Needs tf2onnx (pip install tf2onnx)"""
+
+
+import json
+import re
+
+import tensorflow as tf
+import tf2onnx
+
+from load_and_save_model import load
+
+
+if __name__ == "__main__":
+    with open("constants.jsonc", "r") as f:
+        constants = json.loads(re.sub("//.*", "", f.read(), flags=re.MULTILINE))
+
+    model = load(constants["NET_PATH"])
+    # any batch size, same input name as the saved model
+    input_signature = [
+        tf.TensorSpec(
+            [None, *constants["GAME_STATE_SHAPE"]], tf.float32, name="main_input"
+        )
+    ]
+    tf2onnx.convert.from_keras(
+        model,
+        input_signature=input_signature,
+        output_path=constants["ONNX_PATH"],
+    )
+    print("Exported", constants["NET_PATH"], "to", constants["ONNX_PATH"])
@@ -1,12 +1,16 @@
-"""Script that clear and initialize all training data"""
+"""Script that clears all training data.
+Not needed on a fresh checkout, self-play creates the data directory itself"""
 
-import numpy as np
+import glob
+import os
+
+from training_data import INDEX_FILE
 
 if __name__ == "__main__":
-    with open("training_data/game_state_data.npy", "wb") as f:
-        np.save(f, np.empty((0, 13, 13, 9), dtype=bool))
-    with open("training_data/pi_data.npy", "wb") as f:
-        np.save(f, np.empty((0, 13, 13, 1), dtype=np.float32))
-    with open("training_data/result_data.npy", "wb") as f:
-        np.save(f, np.empty((0,), dtype=np.float32))
+    path = "training_data"
+    os.makedirs(path, exist_ok=True)
+    for file in glob.glob(os.path.join(path, "shard-*")):
+        os.remove(file)
+    with open(os.path.join(path, INDEX_FILE), "w"):
+        pass
     print("INITIALIZED DATA")
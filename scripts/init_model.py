@@ -4,6 +4,7 @@
 import tensorflow as tf
 from tensorflow import keras
 from tensorflow.keras import layers, regularizers, optimizers
+from load_and_save_model import save, VALUE_LOSS_WEIGHT, POLICY_LOSS_WEIGHT
 import os
 import json
 import re
@@ -122,8 +123,8 @@ if __name__ == "__main__":
             learning_rate=constants["LEARNING_RATE"], momentum=constants["MOMENTUM"]
         ),
         # optimizer=optimizers.Adam(learning_rate=constants["LEARNING_RATE"]),
-        loss_weights={"value_head": 0.1, "policy_head": 0.9},
+        loss_weights={"value_head": VALUE_LOSS_WEIGHT, "policy_head": POLICY_LOSS_WEIGHT},
     )
     print(model.summary())
     keras.utils.plot_model(model, "models\\graph.png", show_shapes=True)
-    model.save(constants["NET_PATH"])
+    save(model, constants["NET_PATH"])
@@ -1,6 +1,14 @@
+import os
+
 import tensorflow as tf
 from tensorflow import keras
 
+# loss weights of the heads, as compiled in init_model.py
+VALUE_LOSS_WEIGHT = 0.1
+POLICY_LOSS_WEIGHT = 0.9
+# written by the Rust trainer (src/net/training.rs), holds the prefix of the newest checkpoint
+LATEST_CHECKPOINT = os.path.join("checkpoints", "latest")
+
 
 def softmax_cross_entropy_with_masking(y_true, y_pred):
     mask = tf.equal(y_true, 0.0)
@@ -20,6 +28,11 @@ def softmax_cross_entropy_with_masking(y_true, y_pred):
     return loss
 
 
+def checkpoint_names(model):
+    """Keys of the model weights in the checkpoints, in the order of model.variables"""
+    return [f"var_{i}" for i in range(len(model.variables))]
+
+
 def load(path):
     model = keras.models.load_model(
         path,
@@ -27,8 +40,92 @@ def load(path):
             "softmax_cross_entropy_with_masking": softmax_cross_entropy_with_masking
         },
     )
+    # pick up the weights trained in Rust since the model was last saved
+    latest = os.path.join(path, LATEST_CHECKPOINT)
+    if os.path.isfile(latest):
+        with open(latest, "r") as f:
+            prefix = os.path.join(path, f.read().strip())
+        names = checkpoint_names(model)
+        restored = tf.raw_ops.RestoreV2(
+            prefix=prefix,
+            tensor_names=names,
+            shape_and_slices=[""] * len(names),
+            dtypes=[w.dtype for w in model.variables],
+        )
+        for w, value in zip(model.variables, restored):
+            w.assign(value)
     return model
 
 
 def save(model, path):
-    model.save(path)
+    """Saves the model with the signatures the Rust side uses:
+    serving_default for inference, and train_step, save_checkpoint and restore_checkpoint for training"""
+    input_spec = tf.TensorSpec(
+        [None, *model.input_shape[1:]], tf.float32, name="main_input"
+    )
+    weights = model.variables
+    names = checkpoint_names(model)
+
+    @tf.function(input_signature=[input_spec])
+    def serving(main_input):
+        value, policy = model(main_input, training=False)
+        return {"value_head": value, "policy_head": policy}
+
+    @tf.function(
+        input_signature=[
+            input_spec,
+            tf.TensorSpec([None, 1], tf.float32, name="value_target"),
+            tf.TensorSpec(
+                [None, *model.output_shape[1][1:]], tf.float32, name="policy_target"
+            ),
+        ]
+    )
+    def train_step(main_input, value_target, policy_target):
+        with tf.GradientTape() as tape:
+            value, policy = model(main_input, training=True)
+            value_loss = tf.reduce_mean(tf.square(value_target - value))
+            policy_loss = softmax_cross_entropy_with_masking(policy_target, policy)
+            loss = (
+                VALUE_LOSS_WEIGHT * value_loss
+                + POLICY_LOSS_WEIGHT * policy_loss
+                + sum(model.losses)
+            )
+        grads = tape.gradient(loss, model.trainable_variables)
+        model.optimizer.apply_gradients(zip(grads, model.trainable_variables))
+        return {"loss": loss, "value_loss": value_loss, "policy_loss": policy_loss}
+
+    @tf.function(input_signature=[tf.TensorSpec([], tf.string, name="prefix")])
+    def save_checkpoint(prefix):
+        tf.raw_ops.SaveV2(
+            prefix=prefix,
+            tensor_names=names,
+            shape_and_slices=[""] * len(names),
+            tensors=weights,
+        )
+        return {"prefix": prefix}
+
+    @tf.function(input_signature=[tf.TensorSpec([], tf.string, name="prefix")])
+    def restore_checkpoint(prefix):
+        restored = tf.raw_ops.RestoreV2(
+            prefix=prefix,
+            tensor_names=names,
+            shape_and_slices=[""] * len(names),
+            dtypes=[w.dtype for w in weights],
+        )
+        for w, value in zip(weights, restored):
+            w.assign(value)
+        return {"prefix": prefix}
+
+    model.save(
+        path,
+        signatures={
+            "serving_default": serving,
+            "train_step": train_step,
+            "save_checkpoint": save_checkpoint,
+            "restore_checkpoint": restore_checkpoint,
+        },
+    )
+    # the saved weights now include the checkpointed ones
+    latest = os.path.join(path, LATEST_CHECKPOINT)
+    if os.path.isfile(latest):
+        os.remove(latest)
@@ -1,7 +1,9 @@
-"""Script that train the model using the training data"""
+"""Script that train the model using the training data.
+The positions are sampled from the replay window by `cargo run -- export-batch`"""
 
 import numpy as np
 from load_and_save_model import load, save
+from training_data import export_batch, read_batch, value_targets
 import json
 import re
 import os
@@ -12,34 +14,14 @@ if __name__ == "__main__":
     with open("constants.jsonc", "r") as f:
         constants = json.loads(re.sub("//.*", "", f.read(), flags=re.MULTILINE))
     print("LOADING DATA")
-    with open(constants["TRAINING_DATA_PATH"] + "game_state_data.npy", "rb") as f:
-        game_state_data = np.load(f)
-    with open(constants["TRAINING_DATA_PATH"] + "pi_data.npy", "rb") as f:
-        pi_data = np.load(f)
-    with open(constants["TRAINING_DATA_PATH"] + "result_data.npy", "rb") as f:
-        result_data = np.load(f)
-    print("LOADED DATA")
-    if not (game_state_data.shape[0] == pi_data.shape[0] == result_data.shape[0]):
-        print("DATAS HAVE DIFFERENT LENGTHS, QUITTING")
-        exit()
-    else:
-        num_data = result_data.shape[0]
-        print("NUM DATA: ", result_data.shape[0])
-        if num_data > constants["MAX_SAMPLE_BOARD_FOR_TRAINING"]:
-            print("Larger than MAX_SAMPLE_BOARD_FOR_TRAINING, removing some old data")
-            game_state_data = game_state_data[
-                -constants["MAX_SAMPLE_BOARD_FOR_TRAINING"] :
-            ]
-            pi_data = pi_data[-constants["MAX_SAMPLE_BOARD_FOR_TRAINING"] :]
-            result_data = result_data[-constants["MAX_SAMPLE_BOARD_FOR_TRAINING"] :]
-            with open(
-                constants["TRAINING_DATA_PATH"] + "game_state_data.npy", "wb"
-            ) as f:
-                np.save(f, game_state_data)
-            with open(constants["TRAINING_DATA_PATH"] + "pi_data.npy", "wb") as f:
-                np.save(f, pi_data)
-            with open(constants["TRAINING_DATA_PATH"] + "result_data.npy", "wb") as f:
-                np.save(f, result_data)
+    export_batch(constants["BATCH_PATH"])
+    game_state_data, pi_data, outcome_data, root_value_data = read_batch(
+        constants["BATCH_PATH"]
+    )
+    result_data = value_targets(
+        outcome_data, root_value_data, constants["VALUE_TARGET_Q_WEIGHT"]
+    )
+    print("NUM DATA: ", result_data.shape[0])
 
     print("augmenting data")
     symmetries = [
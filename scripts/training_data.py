@@ -0,0 +1,108 @@
+"""Reads the training data written by the Rust side (src/shards.rs):
+numbered .npz shards in TRAINING_DATA_PATH, listed oldest first in index.txt as `<file> <positions>`,
+each with the records of its games in a .games.jsonl of the same name (src/game_record.rs)"""
+
+import json
+import os
+import subprocess
+import tempfile
+
+import numpy as np
+
+INDEX_FILE = "index.txt"
+
+
+def read_index(path):
+    """(file, number of positions) of every shard, oldest first"""
+    shards = []
+    with open(os.path.join(path, INDEX_FILE), "r") as f:
+        for line in f:
+            if line.strip():
+                file, length = line.split()
+                shards.append((file, int(length)))
+    return shards
+
+
+def read_shard(path, file, length):
+    """game states, pis, results and root values of a shard.
+    Shards written before the root values were recorded use the results instead,
+    and the ones storing their games as moves are expanded by the Rust side"""
+    with np.load(os.path.join(path, file)) as shard:
+        compact = "moves" in shard
+    if compact:
+        with tempfile.TemporaryDirectory() as temp:
+            out = os.path.join(temp, "shard.npz")
+            export_batch(out, os.path.join(path, file))
+            game_states, pis, outcomes, root_values = read_batch(out)
+    else:
+        with np.load(os.path.join(path, file)) as shard:
+            game_states, pis, outcomes = (
+                shard["game_states"],
+                shard["pis"],
+                shard["outcomes"],
+            )
+            root_values = shard["root_values"] if "root_values" in shard else outcomes
+    if not (
+        len(game_states) == len(pis) == len(outcomes) == len(root_values) == length
+    ):
+        raise ValueError(f"{file} doesn't hold the {length} positions of the index")
+    return game_states, pis, outcomes, root_values
+
+
+def read_records(path, file):
+    """Records of the games of a shard, as dicts (none for data converted from the old format)"""
+    records_file = os.path.join(path, file[: -len(".npz")] + ".games.jsonl")
+    if not os.path.isfile(records_file):
+        return []
+    with open(records_file, "r") as f:
+        return [json.loads(line) for line in f if line.strip()]
+
+
+def iter_shards(path, shards=None):
+    """Yields the game states, pis, results and root values of each shard in turn"""
+    for file, length in read_index(path) if shards is None else shards:
+        yield read_shard(path, file, length)
+
+
+def load_latest(path, max_len):
+    """The max_len most recent positions, only reading the shards they are in"""
+    shards = read_index(path)
+    total = 0
+    first = 0
+    for i in reversed(range(len(shards))):
+        total += shards[i][1]
+        if total >= max_len:
+            first = i
+            break
+    parts = list(iter_shards(path, shards[first:]))
+    if not parts:
+        return (
+            np.empty((0, 13, 13, 9), dtype=bool),
+            np.empty((0, 13, 13, 1), dtype=np.float32),
+            np.empty((0,), dtype=np.float32),
+            np.empty((0,), dtype=np.float32),
+        )
+    return tuple(np.concatenate(arrays)[-max_len:] for arrays in zip(*parts))
+
+
+def export_batch(out, shard=None):
+    """Runs `cargo run -- export-batch`, writing a sample of the replay window to out,
+    or every position of the shard file"""
+    command = ["cargo", "run", "--", "export-batch", "--out", out]
+    if shard is not None:
+        command += ["--shard", shard]
+    subprocess.run(command, check=True)
+
+
+def read_batch(file):
+    """game states, pis, results and root values of a training batch written by
+    `cargo run -- export-batch`"""
+    with np.load(file) as batch:
+        return tuple(
+            batch[name] for name in ["game_states", "pis", "outcomes", "root_values"]
+        )
+
+
+def value_targets(outcomes, root_values, q_weight):
+    """What the value head learns, see VALUE_TARGET_Q_WEIGHT in src/constants.rs"""
+    return (1 - q_weight) * outcomes + q_weight * root_values
@@ -0,0 +1,272 @@
+//! Compact encoding of the positions of a shard: the moves of every game and the sparse visit counts
+//! of the search, instead of dense game states and pis. They are expanded back into
+//! `GAME_STATE_SHAPE` and `MOVE_SHAPE` tensors when read, by replaying the games
+use std::io::{Read, Seek, Write};
+
+use ndarray::{Array1, Array2, Array3, Array4, Axis};
+use ndarray_npy::{NpzReader, NpzWriter, ReadNpzError, WriteNpzError};
+
+use crate::constants::sizes;
+use crate::game_record::GameRecord;
+use crate::rules::types::{GameState, Move};
+use crate::types::ReplayData;
+
+// names of the arrays in a shard
+pub const MOVES: &str = "moves.npy";
+const GAME_LENGTHS: &str = "game_lengths.npy";
+const PI_EXPONENTS: &str = "pi_exponents.npy";
+const VISIT_OFFSETS: &str = "visit_offsets.npy";
+const VISIT_MOVES: &str = "visit_moves.npy";
+const VISIT_COUNTS: &str = "visit_counts.npy";
+pub const OUTCOMES: &str = "outcomes.npy";
+pub const ROOT_VALUES: &str = "root_values.npy";
+
+/// The positions of some games, `n` positions in total
+#[derive(PartialEq, Debug)]
+pub struct CompactGames {
+    /// `[x, y]` of the move played in every position, `[n, 2]`
+    moves: Array2<u8>,
+    /// Number of positions of every game
+    game_lengths: Array1<u32>,
+    /// Power the visit counts of every game are raised to, to get pi
+    pi_exponents: Array1<f32>,
+    /// The visits of position `i` are at `visit_offsets[i]..visit_offsets[i + 1]`, `[n + 1]`
+    visit_offsets: Array1<u32>,
+    /// `[x, y]` of every visited move, `[visits, 2]`
+    visit_moves: Array2<u8>,
+    visit_counts: Array1<u32>,
+    outcomes: Array1<f32>,
+    root_values: Array1<f32>,
+}
+
+impl CompactGames {
+    /// Encodes the finished games of `records`
+    pub fn encode(records: &[GameRecord]) -> Self {
+        let mut moves = Vec::new();
+        let mut visit_offsets = vec![0];
+        let mut visit_moves = Vec::new();
+        let mut visit_counts = Vec::new();
+        let mut outcomes = Vec::new();
+        let mut root_values = Vec::new();
+        for record in records {
+            for (turn, position) in record.turns.iter().zip(record.positions()) {
+                moves.extend([turn.mv.x as u8, turn.mv.y as u8]);
+                for &[x, y, visits] in turn.visits.iter().filter(|visit| visit[2] > 0) {
+                    visit_moves.extend([x as u8, y as u8]);
+                    visit_counts.push(visits as u32);
+                }
+                visit_offsets.push(visit_counts.len() as u32);
+                outcomes.push(record.result.outcome_for_side(position.get_side()));
+                root_values.push(turn.root_value);
+            }
+        }
+        CompactGames {
+            moves: Array2::from_shape_vec((moves.len() / 2, 2), moves).unwrap(),
+            game_lengths: records
+                .iter()
+                .map(|record| record.turns.len() as u32)
+                .collect(),
+            pi_exponents: records
+                .iter()
+                .map(|record| record.search.pi_exponent())
+                .collect(),
+            visit_offsets: Array1::from(visit_offsets),
+            visit_moves: Array2::from_shape_vec((visit_moves.len() / 2, 2), visit_moves).unwrap(),
+            visit_counts: Array1::from(visit_counts),
+            outcomes: Array1::from(outcomes),
+            root_values: Array1::from(root_values),
+        }
+    }
+
+    /// Number of positions
+    pub fn len(&self) -> usize {
+        self.outcomes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.outcomes.is_empty()
+    }
+
+    /// Replays the games to get the dense game states and pis
+    pub fn decode(&self) -> Result<ReplayData, String> {
+        self.check()?;
+        let len = self.len();
+        let mut game_states = Array4::from_elem(
+            (
+                len,
+                sizes::GAME_STATE_HEIGHT,
+                sizes::GAME_STATE_WIDTH,
+                sizes::GAME_STATE_PLANES,
+            ),
+            false,
+        );
+        let mut pis = Array4::zeros((
+            len,
+            sizes::MOVE_WIDTH,
+            sizes::MOVE_HEIGHT,
+            sizes::MOVE_PLANES,
+        ));
+        let mut i = 0;
+        for (&game_length, &exponent) in self.game_lengths.iter().zip(&self.pi_exponents) {
+            let mut game_state = GameState::init_game_state();
+            for _ in 0..game_length {
+                game_states
+                    .index_axis_mut(Axis(0), i)
+                    .assign(&game_state.get_contents_clone());
+                pis.index_axis_mut(Axis(0), i)
+                    .assign(&self.pi(i, exponent)?);
+                let mv = to_move(self.moves[[i, 0]], self.moves[[i, 1]])?;
+                if !game_state.get_legal_moves(None).contains(&mv) {
+                    return Err(format!("position {} has the illegal move {:?}", i, mv));
+                }
+                game_state.move_game(mv, None);
+                i += 1;
+            }
+        }
+        Ok(ReplayData {
+            game_states,
+            pis,
+            outcomes: self.outcomes.clone(),
+            root_values: self.root_values.clone(),
+        })
+    }
+
+    /// Pi of position `i`, computed from the visits the same way as the search does
+    fn pi(&self, i: usize, exponent: f32) -> Result<Array3<f32>, String> {
+        let visits = self.visit_offsets[i] as usize..self.visit_offsets[i + 1] as usize;
+        let sum_n: u32 = self.visit_counts.slice(ndarray::s![visits.clone()]).sum();
+        let mut pi = Array3::zeros(sizes::MOVE_SHAPE);
+        let mut sum_weights = 0.0;
+        for v in visits {
+            let mv = to_move(self.visit_moves[[v, 0]], self.visit_moves[[v, 1]])?;
+            let weight = (self.visit_counts[v] as f32 / sum_n as f32).powf(exponent);
+            pi[mv.get_move_arr()] = weight;
+            sum_weights += weight;
+        }
+        Ok(pi / sum_weights)
+    }
+
+    /// Checks that the arrays agree with each other
+    fn check(&self) -> Result<(), String> {
+        let len = self.len();
+        let num_games = self.game_lengths.len();
+        let num_visits = self.visit_counts.len();
+        let consistent = self.moves.len_of(Axis(0)) == len
+            && self.root_values.len() == len
+            && self.game_lengths.iter().map(|&l| l as usize).sum::<usize>() == len
+            && self.pi_exponents.len() == num_games
+            && self.visit_offsets.len() == len + 1
+            && self
+                .visit_offsets
+                .windows(2)
+                .into_iter()
+                .all(|w| w[0] <= w[1])
+            && self.visit_offsets.last() == Some(&(num_visits as u32))
+            && self.visit_moves.len_of(Axis(0)) == num_visits;
+        if consistent {
+            Ok(())
+        } else {
+            Err(format!(
+                "the {} games of {} positions don't match their moves and visits",
+                num_games, len
+            ))
+        }
+    }
+
+    pub fn write<W: Write + Seek>(&self, npz: &mut NpzWriter<W>) -> Result<(), WriteNpzError> {
+        npz.add_array(MOVES, &self.moves)?;
+        npz.add_array(GAME_LENGTHS, &self.game_lengths)?;
+        npz.add_array(PI_EXPONENTS, &self.pi_exponents)?;
+        npz.add_array(VISIT_OFFSETS, &self.visit_offsets)?;
+        npz.add_array(VISIT_MOVES, &self.visit_moves)?;
+        npz.add_array(VISIT_COUNTS, &self.visit_counts)?;
+        npz.add_array(OUTCOMES, &self.outcomes)?;
+        npz.add_array(ROOT_VALUES, &self.root_values)?;
+        Ok(())
+    }
+
+    pub fn read<R: Read + Seek>(npz: &mut NpzReader<R>) -> Result<Self, ReadNpzError> {
+        Ok(CompactGames {
+            moves: npz.by_name(MOVES)?,
+            game_lengths: npz.by_name(GAME_LENGTHS)?,
+            pi_exponents: npz.by_name(PI_EXPONENTS)?,
+            visit_offsets: npz.by_name(VISIT_OFFSETS)?,
+            visit_moves: npz.by_name(VISIT_MOVES)?,
+            visit_counts: npz.by_name(VISIT_COUNTS)?,
+            outcomes: npz.by_name(OUTCOMES)?,
+            root_values: npz.by_name(ROOT_VALUES)?,
+        })
+    }
+}
+
+fn to_move(x: u8, y: u8) -> Result<Move, String> {
+    let (x, y) = (x as usize, y as usize);
+    if x < sizes::MOVE_WIDTH && y < sizes::MOVE_HEIGHT {
+        Ok(Move::new(x, y))
+    } else {
+        Err(format!("the move {} {} is off the board", x, y))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util;
+    use std::io::Cursor;
+
+    /// Plays `len` moves of a game, returning its record and the positions seen during the game
+    fn play(len: usize, seed: u64) -> (GameRecord, ReplayData) {
+        let mut game_states = Vec::new();
+        let mut pis = Vec::new();
+        let mut root_values = Vec::new();
+        let mut record = test_util::play_with(len, seed, true, |game_state, output| {
+            game_states.push(game_state.get_contents_clone());
+            pis.push(output.pi.clone());
+            root_values.push(output.root_value);
+        });
+        // pretend the game ended there, O played last
+        let result = crate::rules::types::GameResult::OWins;
+        record.finish(result);
+        let game_states: Vec<_> = game_states.iter().map(Array3::view).collect();
+        let pis: Vec<_> = pis.iter().map(Array3::view).collect();
+        let dense = ReplayData {
+            game_states: ndarray::stack(Axis(0), &game_states).unwrap(),
+            pis: ndarray::stack(Axis(0), &pis).unwrap(),
+            outcomes: (0..len)
+                .map(|i| if i % 2 == 0 { -1.0 } else { 1.0 })
+                .collect(),
+            root_values: Array1::from(root_values),
+        };
+        (record, dense)
+    }
+
+    #[test]
+    fn round_trip_test() {
+        let (first, first_dense) = play(5, 1);
+        let (second, second_dense) = play(3, 2);
+        let records = vec![first, second];
+        let dense = ReplayData::concatenate(vec![first_dense, second_dense]).unwrap();
+
+        let compact = CompactGames::encode(&records);
+        assert_eq!(compact.len(), 8);
+        let mut npz = NpzWriter::new(Cursor::new(Vec::new()));
+        compact.write(&mut npz).unwrap();
+        let bytes = npz.finish().unwrap().into_inner();
+        let read = CompactGames::read(&mut NpzReader::new(Cursor::new(bytes)).unwrap()).unwrap();
+        assert_eq!(read, compact);
+
+        let decoded = read.decode().unwrap();
+        assert_eq!(decoded.game_states, dense.game_states);
+        assert_eq!(decoded.pis, dense.pis);
+        assert_eq!(decoded.outcomes, dense.outcomes);
+        assert_eq!(decoded.root_values, dense.root_values);
+    }
+
+    #[test]
+    fn rejects_inconsistent_games() {
+        let (record, _) = play(2, 3);
+        let mut compact = CompactGames::encode(&[record]);
+        compact.game_lengths[0] = 3;
+        assert!(compact.decode().is_err());
+    }
+}
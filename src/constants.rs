@@ -4,6 +4,8 @@ use std::{collections::HashMap, fmt::Debug};
 pub const BLOCKED_HEADS_RULE: bool = false;
 pub const MASKING_VALUE: f32 = -100.0;
 pub const NUM_GAME_PER_STEP: usize = 150;
+// games written together to one shard of TRAINING_DATA_PATH
+pub const NUM_GAME_PER_SHARD: usize = 50;
 
 pub const TRAINING_DATA_PATH: &str = "training_data/";
 pub const LOG_PATH: &str = "logs/";
@@ -45,25 +47,65 @@ pub mod mcts {
     pub const DIRICHLET_WEIGHT: f32 = 0.25;
     pub const SECONDARY_DIRICHLET_WEIGHT: f32 = 0.01;
 }
+pub mod inference {
+    pub const MAX_BATCH: usize = super::NUM_THREADS;
+    pub const MAX_WAIT_MS: u64 = 2;
+    pub const CACHE_CAPACITY: usize = 50000;
+    // can be overridden with --symmetry none|random|average
+    pub const SYMMETRY_MODE: crate::evaluator::SymmetryMode = crate::evaluator::SymmetryMode::None;
+}
+pub mod tactics {
+    // depths are counted in attacker moves
+    pub const VCF_MAX_DEPTH: usize = 12;
+    pub const VCT_MAX_DEPTH: usize = 5;
+    pub const TIME_LIMIT_MS: u64 = 1000;
+}
 pub mod model {
     pub const NET_PATH: &str = "models/CaroZero";
+    // written by scripts/export_onnx.py
+    pub const ONNX_PATH: &str = "models/CaroZero.onnx";
+    // written by scripts/export_native.py
+    pub const NATIVE_PATH: &str = "models/CaroZero_native";
     pub const NUM_HIDDEN_RES_BLOCK: usize = 2;
     pub const NUM_FILTERS: usize = 8;
     pub const KERNEL_SIZE: (usize, usize) = (5, 5);
     pub const LEARNING_RATE: f32 = 0.0001;
     pub const MOMENTUM: f32 = 0.9;
     pub const REG_CONST: f32 = 0.0001;
+    // --watch swaps in a newly trained model once its files stayed unchanged this long
+    pub const RELOAD_SETTLE_MS: u64 = 2000;
     pub mod training {
+        // positions sampled from the replay window for every training run
         pub const MAX_SAMPLE_BOARD_FOR_TRAINING: usize = 50000;
         pub const MINI_BATCH: usize = 128;
         pub const NUM_EPOCH: usize = 1;
+        // the value head learns (1 - w) * outcome + w * root value of the search:
+        // 0 is the game outcome, 1 the root Q, anything between a blend
+        pub const VALUE_TARGET_Q_WEIGHT: f32 = 0.0;
+        // losses of every step trained from Rust (cargo run -- train)
+        pub const LOSS_HISTORY_PATH: &str = "models/loss_history.csv";
     }
 }
 
+pub mod replay {
+    use crate::replay_buffer::{Sampling, Window, WindowUnit};
+    // training samples the last WINDOW.min games, plus WINDOW.growth for every game played, up to WINDOW.max
+    pub const WINDOW: Window = Window {
+        unit: WindowUnit::Games,
+        min: 1000,
+        max: 10000,
+        growth: 0.25,
+    };
+    pub const SAMPLING: Sampling = Sampling::Uniform;
+    // written by `cargo run -- export-batch` for scripts/model_trainer.py
+    pub const BATCH_PATH: &str = "training_data/batch.npz";
+}
+
 /// Write constants to constants.jsonc for scripts to read
 pub fn write_constants_to_file() -> Result<(), Box<dyn std::error::Error>> {
     use model::training::*;
     use model::*;
+    use replay::*;
     use sizes::*;
     use std::any::Any;
     use std::fs;
@@ -89,6 +131,8 @@ pub fn write_constants_to_file() -> Result<(), Box<dyn std::error::Error>> {
         MOVE_SHAPE,
         TRAINING_DATA_PATH,
         NET_PATH,
+        ONNX_PATH,
+        NATIVE_PATH,
         NUM_HIDDEN_RES_BLOCK,
         NUM_FILTERS,
         KERNEL_SIZE,
@@ -96,7 +140,9 @@ pub fn write_constants_to_file() -> Result<(), Box<dyn std::error::Error>> {
         MOMENTUM,
         REG_CONST,
         MAX_SAMPLE_BOARD_FOR_TRAINING,
+        BATCH_PATH,
         MINI_BATCH,
+        VALUE_TARGET_Q_WEIGHT,
         NUM_EPOCH
     ];
 
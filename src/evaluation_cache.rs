@@ -0,0 +1,294 @@
+//! Bounded LRU cache of evaluations, so the same position isn't evaluated twice
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+use crate::evaluator::{Evaluation, Evaluator, ModelId};
+use crate::net::NetError;
+use crate::rules::types::GameState;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CacheStats {
+    pub hits: usize,
+    pub misses: usize,
+    pub len: usize,
+    pub capacity: usize,
+}
+
+impl CacheStats {
+    pub fn hit_rate(&self) -> f32 {
+        let total = self.hits + self.misses;
+        if total == 0 {
+            0.0
+        } else {
+            self.hits as f32 / total as f32
+        }
+    }
+}
+
+struct Entry {
+    evaluation: Evaluation,
+    last_used: u64,
+}
+
+/// The cache itself, always used behind the mutex in `CachedEvaluator`
+struct Lru {
+    entries: HashMap<u64, Entry>,
+    /// position hashes ordered from least to most recently used
+    recency: BTreeMap<u64, u64>,
+    clock: u64,
+    /// generation of the inner evaluator the entries were made with
+    generation: u64,
+    hits: usize,
+    misses: usize,
+}
+
+impl Lru {
+    fn new(generation: u64) -> Self {
+        Lru {
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            clock: 0,
+            generation,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    fn get(&mut self, key: u64) -> Option<Evaluation> {
+        self.clock += 1;
+        match self.entries.get_mut(&key) {
+            Some(entry) => {
+                self.recency.remove(&entry.last_used);
+                entry.last_used = self.clock;
+                self.recency.insert(self.clock, key);
+                self.hits += 1;
+                Some(entry.evaluation.clone())
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    fn insert(&mut self, key: u64, evaluation: Evaluation, capacity: usize) {
+        if capacity == 0 {
+            return;
+        }
+        self.clock += 1;
+        if let Some(old) = self.entries.insert(
+            key,
+            Entry {
+                evaluation,
+                last_used: self.clock,
+            },
+        ) {
+            self.recency.remove(&old.last_used);
+        }
+        self.recency.insert(self.clock, key);
+        // evict the least recently used entries
+        while self.entries.len() > capacity {
+            let (_, oldest) = self.recency.pop_first().unwrap();
+            self.entries.remove(&oldest);
+        }
+    }
+
+    fn clear(&mut self, generation: u64) {
+        self.entries.clear();
+        self.recency.clear();
+        self.generation = generation;
+    }
+}
+
+/// Wraps any evaluator with a thread-safe LRU cache keyed by `GameState::position_hash`.
+/// The cache is emptied when the inner evaluator's generation changes (i.e. the model is reloaded)
+pub struct CachedEvaluator<E: Evaluator> {
+    inner: E,
+    capacity: usize,
+    lru: Mutex<Lru>,
+}
+
+impl<E: Evaluator> CachedEvaluator<E> {
+    pub fn new(inner: E, capacity: usize) -> Self {
+        let generation = inner.generation();
+        CachedEvaluator {
+            inner,
+            capacity,
+            lru: Mutex::new(Lru::new(generation)),
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let lru = self.lru.lock().unwrap();
+        CacheStats {
+            hits: lru.hits,
+            misses: lru.misses,
+            len: lru.entries.len(),
+            capacity: self.capacity,
+        }
+    }
+
+    /// Throws away every cached evaluation
+    pub fn clear(&self) {
+        self.lru.lock().unwrap().clear(self.inner.generation());
+    }
+
+    /// Locks the cache, emptying it first if the model changed
+    fn lock_current(&self) -> std::sync::MutexGuard<'_, Lru> {
+        let generation = self.inner.generation();
+        let mut lru = self.lru.lock().unwrap();
+        if lru.generation != generation {
+            lru.clear(generation);
+        }
+        lru
+    }
+}
+
+impl<E: Evaluator> Evaluator for CachedEvaluator<E> {
+    fn evaluate(&self, game_state: &GameState) -> Evaluation {
+        self.evaluate_batch(std::slice::from_ref(game_state))
+            .pop()
+            .unwrap()
+    }
+
+    fn evaluate_batch(&self, game_states: &[GameState]) -> Vec<Evaluation> {
+        self.try_evaluate_batch(game_states)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    fn try_evaluate_batch(&self, game_states: &[GameState]) -> Result<Vec<Evaluation>, NetError> {
+        let keys: Vec<u64> = game_states.iter().map(|gs| gs.position_hash()).collect();
+        let mut results: Vec<Option<Evaluation>> = {
+            let mut lru = self.lock_current();
+            keys.iter().map(|&key| lru.get(key)).collect()
+        };
+
+        // evaluate the misses without holding the lock
+        let missing: Vec<usize> = (0..results.len())
+            .filter(|&i| results[i].is_none())
+            .collect();
+        if !missing.is_empty() {
+            let missing_states: Vec<GameState> =
+                missing.iter().map(|&i| game_states[i].clone()).collect();
+            let generation = self.inner.generation();
+            let evaluations = self.inner.try_evaluate_batch(&missing_states)?;
+            let mut lru = self.lock_current();
+            // the model was reloaded meanwhile, the evaluations may come from the old one
+            let current = lru.generation == generation;
+            for (&i, evaluation) in missing.iter().zip(evaluations) {
+                if current {
+                    lru.insert(keys[i], evaluation.clone(), self.capacity);
+                }
+                results[i] = Some(evaluation);
+            }
+        }
+        Ok(results.into_iter().map(Option::unwrap).collect())
+    }
+
+    fn generation(&self) -> u64 {
+        self.inner.generation()
+    }
+    fn model_id(&self) -> ModelId {
+        self.inner.model_id()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::evaluator::UniformEvaluator;
+    use crate::rules::types::Move;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    /// Uniform evaluator whose model can be "reloaded"
+    struct ReloadableEvaluator {
+        generation: AtomicU64,
+    }
+    impl Evaluator for ReloadableEvaluator {
+        fn evaluate(&self, game_state: &GameState) -> Evaluation {
+            UniformEvaluator.evaluate(game_state)
+        }
+        fn generation(&self) -> u64 {
+            self.generation.load(Ordering::SeqCst)
+        }
+    }
+
+    /// Uniform evaluator whose model is reloaded while it evaluates
+    struct ReloadingEvaluator {
+        generation: AtomicU64,
+    }
+    impl Evaluator for ReloadingEvaluator {
+        fn evaluate(&self, game_state: &GameState) -> Evaluation {
+            self.generation.fetch_add(1, Ordering::SeqCst);
+            UniformEvaluator.evaluate(game_state)
+        }
+        fn generation(&self) -> u64 {
+            self.generation.load(Ordering::SeqCst)
+        }
+    }
+
+    fn positions(n: usize) -> Vec<GameState> {
+        (0..n)
+            .map(|i| {
+                let mut game = GameState::init_game_state();
+                game.move_game(Move::new(i % 13, i / 13), None);
+                game
+            })
+            .collect()
+    }
+
+    #[test]
+    fn hits_and_evictions() {
+        let cache = CachedEvaluator::new(UniformEvaluator, 2);
+        let games = positions(3);
+        cache.evaluate(&games[0]);
+        cache.evaluate(&games[1]);
+        cache.evaluate(&games[0]);
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.len), (1, 2, 2));
+
+        // evicts games[1], the least recently used
+        cache.evaluate(&games[2]);
+        cache.evaluate(&games[0]);
+        cache.evaluate(&games[1]);
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.len), (2, 4, 2));
+        assert!((stats.hit_rate() - 1.0 / 3.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn invalidated_on_reload() {
+        let cache = CachedEvaluator::new(
+            ReloadableEvaluator {
+                generation: AtomicU64::new(0),
+            },
+            10,
+        );
+        let games = positions(4);
+        cache.evaluate_batch(&games);
+        cache.evaluate_batch(&games);
+        assert_eq!(cache.stats().hits, 4);
+
+        cache.inner.generation.store(1, Ordering::SeqCst);
+        cache.evaluate(&games[0]);
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.len), (4, 5, 1));
+    }
+
+    #[test]
+    fn reload_during_evaluation() {
+        let cache = CachedEvaluator::new(
+            ReloadingEvaluator {
+                generation: AtomicU64::new(0),
+            },
+            10,
+        );
+        let games = positions(2);
+        cache.evaluate_batch(&games);
+        // evaluated by the old model, so not cached under the new one
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.len), (0, 2, 0));
+        cache.evaluate(&games[0]);
+        assert_eq!(cache.stats().hits, 0);
+    }
+}
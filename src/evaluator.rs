@@ -0,0 +1,376 @@
+use std::fmt::{self, Display, Formatter};
+use std::sync::Arc;
+
+use ndarray::Array3;
+use serde::{Deserialize, Serialize};
+
+use crate::constants::sizes;
+use crate::net::NetError;
+use crate::rules::patterns::{Pattern, PatternKind};
+use crate::rules::symmetry::{transform_planes, Symmetry};
+use crate::rules::types::GameState;
+
+/// What an `Evaluator` thinks of a single position
+#[derive(Clone)]
+pub struct Evaluation {
+    /// Expected outcome for the side to move, between -1 and 1
+    pub value: f32,
+    /// Unnormalized score of every move, with shape `sizes::MOVE_SHAPE`
+    pub policy: Array3<f32>,
+}
+
+/// Which model an evaluator runs, written to the logs and game records
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct ModelId {
+    pub name: String,
+    /// When the model files were last written (see `net::model_version`), 0 for evaluators without files
+    pub version: u64,
+}
+
+impl ModelId {
+    pub fn new(name: &str, version: u64) -> Self {
+        ModelId {
+            name: name.to_string(),
+            version,
+        }
+    }
+}
+
+impl Display for ModelId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{} v{}", self.name, self.version)
+    }
+}
+
+/// Anything that can guide the tree search: gives the value of a position
+/// and a policy over the moves playable from it
+pub trait Evaluator {
+    fn evaluate(&self, game_state: &GameState) -> Evaluation;
+
+    /// Evaluates several positions at once, evaluators that can batch should override this
+    fn evaluate_batch(&self, game_states: &[GameState]) -> Vec<Evaluation> {
+        game_states.iter().map(|gs| self.evaluate(gs)).collect()
+    }
+
+    /// Same as `evaluate_batch`, but returns the error of an evaluator that can fail (a net)
+    /// instead of panicking. Those override it, and so do the evaluators that wrap another one
+    fn try_evaluate_batch(&self, game_states: &[GameState]) -> Result<Vec<Evaluation>, NetError> {
+        Ok(self.evaluate_batch(game_states))
+    }
+
+    /// Changes every time the evaluator's model is reloaded,
+    /// so that evaluations made with the old model can be thrown away
+    fn generation(&self) -> u64 {
+        0
+    }
+
+    fn model_id(&self) -> ModelId {
+        ModelId::new("unnamed", self.generation())
+    }
+}
+
+impl<E: Evaluator + ?Sized> Evaluator for Arc<E> {
+    fn evaluate(&self, game_state: &GameState) -> Evaluation {
+        self.as_ref().evaluate(game_state)
+    }
+    fn evaluate_batch(&self, game_states: &[GameState]) -> Vec<Evaluation> {
+        self.as_ref().evaluate_batch(game_states)
+    }
+    fn try_evaluate_batch(&self, game_states: &[GameState]) -> Result<Vec<Evaluation>, NetError> {
+        self.as_ref().try_evaluate_batch(game_states)
+    }
+    fn generation(&self) -> u64 {
+        self.as_ref().generation()
+    }
+    fn model_id(&self) -> ModelId {
+        self.as_ref().model_id()
+    }
+}
+
+/// Plays every legal move with the same probability and thinks every position is even.
+/// Needs no model, so it's handy for tests
+pub struct UniformEvaluator;
+
+impl Evaluator for UniformEvaluator {
+    fn evaluate(&self, _game_state: &GameState) -> Evaluation {
+        Evaluation {
+            value: 0.0,
+            policy: Array3::zeros(sizes::MOVE_SHAPE),
+        }
+    }
+    fn model_id(&self) -> ModelId {
+        ModelId::new("uniform", 0)
+    }
+}
+
+/// Handcrafted evaluator based on the line patterns of both sides.
+/// Used to bootstrap the first generation of training data and as a baseline opponent
+pub struct HeuristicEvaluator;
+
+impl HeuristicEvaluator {
+    /// How much a pattern is worth to its owner, used for the value
+    fn pattern_value(kind: PatternKind) -> f32 {
+        match kind {
+            PatternKind::Five => 10.0,
+            PatternKind::OpenFour => 1.0,
+            PatternKind::Four => 0.4,
+            PatternKind::OpenThree => 0.3,
+            PatternKind::BrokenThree => 0.25,
+            PatternKind::ClosedThree => 0.1,
+        }
+    }
+    /// How much completing a pattern is worth, used for the policy
+    fn completion_score(kind: PatternKind, own: bool) -> f32 {
+        match (kind, own) {
+            (PatternKind::Four | PatternKind::OpenFour, true) => 10.0,
+            (PatternKind::Four | PatternKind::OpenFour, false) => 8.0,
+            (PatternKind::OpenThree | PatternKind::BrokenThree, true) => 4.0,
+            (PatternKind::OpenThree | PatternKind::BrokenThree, false) => 3.0,
+            (PatternKind::ClosedThree, true) => 1.5,
+            (PatternKind::ClosedThree, false) => 1.0,
+            (PatternKind::Five, _) => 0.0,
+        }
+    }
+}
+
+impl Evaluator for HeuristicEvaluator {
+    fn evaluate(&self, game_state: &GameState) -> Evaluation {
+        let board = game_state.get_board_view();
+        let side = board.get_side();
+        let own_patterns = board.find_patterns(side);
+        let opponent_patterns = board.find_patterns(side.opponent());
+
+        // policy: prefer squares next to pieces, then squares completing patterns
+        let mut policy = Array3::zeros(sizes::MOVE_SHAPE);
+        for mv in board.get_legal_moves(Some(side)) {
+            let near =
+                (mv.y.saturating_sub(1)..=(mv.y + 1).min(sizes::BOARD_HEIGHT - 1)).any(|y| {
+                    (mv.x.saturating_sub(1)..=(mv.x + 1).min(sizes::BOARD_WIDTH - 1))
+                        .any(|x| !board.is_empty_grid(x, y))
+                });
+            if near {
+                policy[mv.get_move_arr()] = 1.0;
+            }
+        }
+        for (patterns, own) in [(&own_patterns, true), (&opponent_patterns, false)] {
+            for pattern in patterns.iter() {
+                for mv in pattern.completions.iter() {
+                    policy[mv.get_move_arr()] += Self::completion_score(pattern.kind, own);
+                }
+            }
+        }
+
+        // value: a four wins on the next move, the opponent's open four (or two fours) can't be stopped
+        let is_four = |p: &&Pattern| matches!(p.kind, PatternKind::Four | PatternKind::OpenFour);
+        let value = if own_patterns.iter().any(|p| is_four(&p)) {
+            1.0
+        } else if opponent_patterns.iter().filter(is_four).count() >= 2
+            || opponent_patterns
+                .iter()
+                .any(|p| p.kind == PatternKind::OpenFour)
+        {
+            -1.0
+        } else {
+            let score = |patterns: &[Pattern]| -> f32 {
+                patterns.iter().map(|p| Self::pattern_value(p.kind)).sum()
+            };
+            (score(&own_patterns) - score(&opponent_patterns)).tanh()
+        };
+
+        Evaluation { value, policy }
+    }
+    fn model_id(&self) -> ModelId {
+        ModelId::new("heuristic", 0)
+    }
+}
+
+/// How `SymmetricEvaluator` uses the symmetries of the board
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SymmetryMode {
+    /// Evaluates the position as given
+    None,
+    /// Evaluates the position through one random symmetry
+    Random,
+    /// Evaluates the position through all 8 symmetries and averages the results
+    Average,
+}
+
+impl SymmetryMode {
+    pub fn from_name(name: &str) -> Option<SymmetryMode> {
+        match name {
+            "none" => Some(SymmetryMode::None),
+            "random" => Some(SymmetryMode::Random),
+            "average" => Some(SymmetryMode::Average),
+            _ => None,
+        }
+    }
+}
+
+/// Evaluates positions through the symmetries of the board, like the network is trained.
+/// The policy is always given in the orientation of the original position
+pub struct SymmetricEvaluator<E: Evaluator> {
+    inner: E,
+    mode: SymmetryMode,
+}
+
+impl<E: Evaluator> SymmetricEvaluator<E> {
+    pub fn new(inner: E, mode: SymmetryMode) -> Self {
+        SymmetricEvaluator { inner, mode }
+    }
+
+    /// Evaluates `game_state` through each of `symmetries`, in one batch
+    fn evaluate_through(
+        &self,
+        game_state: &GameState,
+        symmetries: &[Symmetry],
+    ) -> Result<Vec<Evaluation>, NetError> {
+        let transformed: Vec<GameState> = symmetries
+            .iter()
+            .map(|&symmetry| game_state.transform(symmetry))
+            .collect();
+        Ok(self
+            .inner
+            .try_evaluate_batch(&transformed)?
+            .into_iter()
+            .zip(symmetries)
+            .map(|(evaluation, symmetry)| Evaluation {
+                value: evaluation.value,
+                policy: transform_planes(&evaluation.policy, symmetry.inverse()),
+            })
+            .collect())
+    }
+
+    fn try_evaluate(&self, game_state: &GameState) -> Result<Evaluation, NetError> {
+        Ok(match self.mode {
+            SymmetryMode::None => self
+                .inner
+                .try_evaluate_batch(std::slice::from_ref(game_state))?
+                .remove(0),
+            SymmetryMode::Random => self
+                .evaluate_through(game_state, &[Symmetry::random()])?
+                .remove(0),
+            SymmetryMode::Average => average(&self.evaluate_through(game_state, &Symmetry::ALL)?),
+        })
+    }
+}
+
+/// Averages the evaluations, the policies are averaged as probabilities rather than as logits
+fn average(evaluations: &[Evaluation]) -> Evaluation {
+    let n = evaluations.len() as f32;
+    let value = evaluations.iter().map(|e| e.value).sum::<f32>() / n;
+    let mut probabilities = Array3::zeros(sizes::MOVE_SHAPE);
+    for evaluation in evaluations {
+        let max = evaluation.policy.fold(f32::NEG_INFINITY, |a, &b| a.max(b));
+        let exp = evaluation.policy.mapv(|p| (p - max).exp());
+        probabilities += &(&exp / exp.sum());
+    }
+    Evaluation {
+        value,
+        policy: probabilities.mapv(|p: f32| (p / n).max(f32::MIN_POSITIVE).ln()),
+    }
+}
+
+impl<E: Evaluator> Evaluator for SymmetricEvaluator<E> {
+    fn evaluate(&self, game_state: &GameState) -> Evaluation {
+        self.try_evaluate(game_state)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+    fn evaluate_batch(&self, game_states: &[GameState]) -> Vec<Evaluation> {
+        self.try_evaluate_batch(game_states)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+    fn try_evaluate_batch(&self, game_states: &[GameState]) -> Result<Vec<Evaluation>, NetError> {
+        match self.mode {
+            SymmetryMode::None => self.inner.try_evaluate_batch(game_states),
+            _ => game_states.iter().map(|gs| self.try_evaluate(gs)).collect(),
+        }
+    }
+    fn generation(&self) -> u64 {
+        self.inner.generation()
+    }
+    fn model_id(&self) -> ModelId {
+        self.inner.model_id()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rules::types::Move;
+
+    #[test]
+    fn uniform_evaluator_test() {
+        let evaluation = UniformEvaluator.evaluate(&GameState::init_game_state());
+        assert_eq!(evaluation.value, 0.0);
+        assert!(evaluation.policy.iter().all(|&p| p == 0.0));
+    }
+
+    #[test]
+    fn heuristic_evaluator_test() {
+        let mut game = GameState::init_game_state();
+        for (x, y) in [(3, 5), (0, 0), (4, 5), (12, 0), (5, 5), (0, 12), (6, 5)] {
+            game.move_game(Move::new(x, y), None);
+        }
+        // O to move, has to block X's open four but can't
+        let evaluation = HeuristicEvaluator.evaluate(&game);
+        assert_eq!(evaluation.value, -1.0);
+        let best = evaluation.policy.fold(f32::NEG_INFINITY, |a, &b| a.max(b));
+        assert_eq!(evaluation.policy[[5, 2, 0]], best);
+        assert_eq!(evaluation.policy[[5, 7, 0]], best);
+
+        // X to move, wins
+        game.move_game(Move::new(12, 12), None);
+        let evaluation = HeuristicEvaluator.evaluate(&game);
+        assert_eq!(evaluation.value, 1.0);
+        assert_eq!(evaluation.policy[[5, 7, 0]], 1.0 + 10.0);
+
+        let wrapped = SymmetricEvaluator::new(Arc::new(HeuristicEvaluator), SymmetryMode::Random);
+        assert_eq!(wrapped.model_id(), ModelId::new("heuristic", 0));
+    }
+
+    /// Puts all the policy on the squares X played, so it follows the board around
+    struct XStonesEvaluator;
+
+    impl Evaluator for XStonesEvaluator {
+        fn evaluate(&self, game_state: &GameState) -> Evaluation {
+            let board = game_state.get_board_view();
+            let mut policy = Array3::from_elem(sizes::MOVE_SHAPE, -10.0);
+            for y in 0..sizes::BOARD_HEIGHT {
+                for x in 0..sizes::BOARD_WIDTH {
+                    if board.get_grid(x, y, 0) {
+                        policy[[y, x, 0]] = 10.0;
+                    }
+                }
+            }
+            Evaluation { value: 0.5, policy }
+        }
+    }
+
+    #[test]
+    fn symmetric_evaluator_test() {
+        let mut game = GameState::init_game_state();
+        game.move_game(Move::new(1, 3), None);
+        game.move_game(Move::new(8, 4), None);
+        let best = |evaluation: &Evaluation| {
+            let max = evaluation.policy.fold(f32::NEG_INFINITY, |a, &b| a.max(b));
+            evaluation
+                .policy
+                .indexed_iter()
+                .find(|(_, &p)| p == max)
+                .unwrap()
+                .0
+        };
+        for mode in [
+            SymmetryMode::None,
+            SymmetryMode::Random,
+            SymmetryMode::Average,
+        ] {
+            let evaluator = SymmetricEvaluator::new(XStonesEvaluator, mode);
+            for _ in 0..8 {
+                let evaluation = evaluator.evaluate(&game);
+                assert!((evaluation.value - 0.5).abs() < 1e-6);
+                assert_eq!(best(&evaluation), (3, 1, 0));
+            }
+        }
+    }
+}
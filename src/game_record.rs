@@ -0,0 +1,168 @@
+//! Structured records of the self-play games, written next to their positions in the training data
+//! as JSON Lines, one game per line. The positions of a game can be regenerated from its moves
+use std::io::{self, BufRead, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::constants::mcts;
+use crate::evaluator::ModelId;
+use crate::monte_carlo_tree_search::{self, MCTSOutput};
+use crate::rules::types::{GameResult, GameState, Move};
+
+/// The settings of the tree search that played a game
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct SearchSettings {
+    pub num_search: usize,
+    pub c_puct: f32,
+    pub exploration: f32,
+    pub play_stochastically: bool,
+}
+
+impl SearchSettings {
+    /// The settings in `constants::mcts`
+    pub fn current(play_stochastically: bool) -> Self {
+        SearchSettings {
+            num_search: mcts::NUM_SEARCH,
+            c_puct: mcts::C_PUCT,
+            exploration: mcts::EXPLORATION,
+            play_stochastically,
+        }
+    }
+
+    /// The power the search raised the visit counts to, to get pi
+    pub fn pi_exponent(&self) -> f32 {
+        if self.play_stochastically {
+            1.0 / self.exploration
+        } else {
+            monte_carlo_tree_search::pi_exponent(false)
+        }
+    }
+}
+
+/// One move of a game, with what the search thought of the position
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct TurnRecord {
+    #[serde(rename = "move")]
+    pub mv: Move,
+    /// Mean value of the root after the search
+    pub root_value: f32,
+    /// `[x, y, visits]` of every move of the root
+    pub visits: Vec<[usize; 3]>,
+    /// When the move was played, in milliseconds since the Unix epoch
+    pub time_ms: u64,
+}
+
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct GameRecord {
+    pub model: ModelId,
+    pub search: SearchSettings,
+    /// Seed of the random generator that picked the moves
+    pub seed: u64,
+    pub started_ms: u64,
+    pub finished_ms: u64,
+    pub turns: Vec<TurnRecord>,
+    pub result: GameResult,
+}
+
+impl GameRecord {
+    pub fn new(model: ModelId, search: SearchSettings, seed: u64) -> Self {
+        let now = unix_millis();
+        GameRecord {
+            model,
+            search,
+            seed,
+            started_ms: now,
+            finished_ms: now,
+            turns: Vec::new(),
+            result: GameResult::NotFinished,
+        }
+    }
+
+    /// Records the move picked by the search, and the statistics of its root
+    pub fn push_turn(&mut self, output: &MCTSOutput) {
+        self.turns.push(TurnRecord {
+            mv: output.best_move,
+            root_value: output.root_value,
+            visits: output
+                .visits
+                .iter()
+                .map(|&(mv, visits)| [mv.x, mv.y, visits])
+                .collect(),
+            time_ms: unix_millis(),
+        });
+    }
+
+    pub fn finish(&mut self, result: GameResult) {
+        self.result = result;
+        self.finished_ms = unix_millis();
+    }
+
+    /// The position before every move, replayed from the start
+    pub fn positions(&self) -> Vec<GameState> {
+        let mut game_state = GameState::init_game_state();
+        let mut positions = Vec::with_capacity(self.turns.len());
+        for turn in &self.turns {
+            positions.push(game_state.clone());
+            game_state.move_game(turn.mv, None);
+        }
+        positions
+    }
+}
+
+/// Milliseconds since the Unix epoch
+pub fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_millis() as u64)
+}
+
+/// Writes `records` as JSON Lines
+pub fn write_records<W: Write>(mut writer: W, records: &[GameRecord]) -> io::Result<()> {
+    for record in records {
+        serde_json::to_writer(&mut writer, record)?;
+        writeln!(writer)?;
+    }
+    Ok(())
+}
+
+/// Reads records written by `write_records`
+pub fn read_records<R: BufRead>(reader: R) -> io::Result<Vec<GameRecord>> {
+    reader
+        .lines()
+        .filter(|line| !matches!(line, Ok(line) if line.trim().is_empty()))
+        .map(|line| Ok(serde_json::from_str(&line?)?))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util;
+
+    #[test]
+    fn game_record_test() {
+        let mut positions = Vec::new();
+        let record = test_util::play_with(4, 42, true, |game_state, _| {
+            positions.push(game_state.clone())
+        });
+
+        let mut json = Vec::new();
+        write_records(&mut json, &[record.clone(), record.clone()]).unwrap();
+        assert_eq!(json.iter().filter(|&&c| c == b'\n').count(), 2);
+        let read = read_records(&json[..]).unwrap();
+        assert_eq!(read, vec![record.clone(), record.clone()]);
+
+        // the moves are enough to get the positions back
+        let replayed = record.positions();
+        assert_eq!(replayed.len(), 4);
+        for (replayed, position) in replayed.iter().zip(&positions) {
+            assert_eq!(replayed.get_contents_clone(), position.get_contents_clone());
+        }
+        assert_eq!(record.turns[0].visits.len(), 13 * 13);
+        assert_eq!(
+            record.turns[0].visits.iter().map(|v| v[2]).sum::<usize>(),
+            mcts::NUM_SEARCH - 1
+        );
+    }
+}
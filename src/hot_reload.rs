@@ -0,0 +1,199 @@
+//! Swaps in a new model when its files change, so a long-running process
+//! can keep generating games while the model is trained elsewhere
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+
+use crate::evaluator::{Evaluation, Evaluator, ModelId};
+use crate::net::NetError;
+use crate::rules::types::GameState;
+
+type Loader<E> = Box<dyn Fn(&Path) -> Result<E, Box<dyn Error>> + Send + Sync>;
+
+/// Evaluator for a model directory (or file) that can be reloaded in place.
+/// Every reload bumps `generation`, which also empties any `CachedEvaluator` on top of it
+pub struct ReloadableEvaluator<E: Evaluator> {
+    path: PathBuf,
+    loader: Loader<E>,
+    current: RwLock<Arc<E>>,
+    generation: AtomicU64,
+    /// modification time of the files the current model was loaded from
+    loaded_at: Mutex<Option<SystemTime>>,
+}
+
+impl<E: Evaluator> ReloadableEvaluator<E> {
+    /// Loads the model at `path` with `loader`, which is used again on every reload
+    pub fn new<F>(path: &Path, loader: F) -> Result<Self, Box<dyn Error>>
+    where
+        F: Fn(&Path) -> Result<E, Box<dyn Error>> + Send + Sync + 'static,
+    {
+        let loaded_at = last_modified(path);
+        let model = loader(path)?;
+        Ok(ReloadableEvaluator {
+            path: path.to_path_buf(),
+            loader: Box::new(loader),
+            current: RwLock::new(Arc::new(model)),
+            generation: AtomicU64::new(0),
+            loaded_at: Mutex::new(loaded_at),
+        })
+    }
+
+    /// The model in use right now
+    pub fn current(&self) -> Arc<E> {
+        Arc::clone(&self.current.read().unwrap())
+    }
+
+    /// Whether the files changed since the current model was loaded
+    pub fn is_outdated(&self) -> bool {
+        last_modified(&self.path) != *self.loaded_at.lock().unwrap()
+    }
+
+    /// Loads the model again and swaps it in. If loading fails the old model is kept
+    pub fn reload(&self) -> Result<(), Box<dyn Error>> {
+        self.swap_in(&mut self.loaded_at.lock().unwrap())
+    }
+
+    fn swap_in(&self, loaded_at: &mut Option<SystemTime>) -> Result<(), Box<dyn Error>> {
+        let modified = last_modified(&self.path);
+        let model = (self.loader)(&self.path)?;
+        *self.current.write().unwrap() = Arc::new(model);
+        *loaded_at = modified;
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Reloads if the files changed, returns whether it did
+    pub fn reload_if_changed(&self) -> Result<bool, Box<dyn Error>> {
+        if self.is_outdated() {
+            self.reload()?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    /// Reloads if the files changed and haven't changed for `settle` since (the trainer is done
+    /// writing them), without waiting. Returns whether it did.
+    /// Only one caller reloads at a time, the others carry on with the current model meanwhile
+    pub fn reload_if_settled(&self, settle: Duration) -> Result<bool, Box<dyn Error>> {
+        let mut loaded_at = match self.loaded_at.try_lock() {
+            Ok(loaded_at) => loaded_at,
+            Err(_) => return Ok(false),
+        };
+        let modified = last_modified(&self.path);
+        let settled = modified
+            .and_then(|modified| modified.elapsed().ok())
+            .is_some_and(|age| age >= settle);
+        if modified == *loaded_at || !settled {
+            return Ok(false);
+        }
+        self.swap_in(&mut loaded_at)?;
+        Ok(true)
+    }
+}
+
+impl<E: Evaluator> Evaluator for ReloadableEvaluator<E> {
+    fn evaluate(&self, game_state: &GameState) -> Evaluation {
+        self.current().evaluate(game_state)
+    }
+    fn evaluate_batch(&self, game_states: &[GameState]) -> Vec<Evaluation> {
+        self.current().evaluate_batch(game_states)
+    }
+    fn try_evaluate_batch(&self, game_states: &[GameState]) -> Result<Vec<Evaluation>, NetError> {
+        self.current().try_evaluate_batch(game_states)
+    }
+    fn generation(&self) -> u64 {
+        self.generation.load(Ordering::SeqCst)
+    }
+    fn model_id(&self) -> ModelId {
+        self.current().model_id()
+    }
+}
+
+/// Latest modification time of the files in `path` (and its subdirectories, like `variables/`),
+/// or of `path` itself if it's a file
+pub(crate) fn last_modified(path: &Path) -> Option<SystemTime> {
+    if path.is_file() {
+        return fs::metadata(path).and_then(|m| m.modified()).ok();
+    }
+    let mut latest = None;
+    for entry in fs::read_dir(path).ok()?.flatten() {
+        let modified = if entry.path().is_dir() {
+            last_modified(&entry.path())
+        } else {
+            entry.metadata().and_then(|m| m.modified()).ok()
+        };
+        latest = latest.max(modified);
+    }
+    latest
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::evaluator::UniformEvaluator;
+    use crate::net::model_version;
+    use crate::test_util::TempDir;
+    use std::fs::File;
+
+    /// Uniform evaluator that reports the version of the files it was loaded from
+    struct VersionedEvaluator(ModelId);
+    impl Evaluator for VersionedEvaluator {
+        fn evaluate(&self, game_state: &GameState) -> Evaluation {
+            UniformEvaluator.evaluate(game_state)
+        }
+        fn model_id(&self) -> ModelId {
+            self.0.clone()
+        }
+    }
+
+    #[test]
+    fn reloads_when_files_change() {
+        let dir = TempDir::new("hot_reload");
+        let model_file = File::create(dir.join("saved_model.pb")).unwrap();
+        model_file
+            .set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(1000))
+            .unwrap();
+
+        let evaluator = ReloadableEvaluator::new(&dir, |path| {
+            Ok(VersionedEvaluator(ModelId::new(
+                "test",
+                model_version(path),
+            )))
+        })
+        .unwrap();
+        assert_eq!(evaluator.model_id(), ModelId::new("test", 1000));
+        assert!(!evaluator.reload_if_changed().unwrap());
+
+        model_file
+            .set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(2000))
+            .unwrap();
+        assert!(evaluator.is_outdated());
+        assert!(evaluator.reload_if_changed().unwrap());
+        assert_eq!(evaluator.generation(), 1);
+        assert_eq!(evaluator.model_id(), ModelId::new("test", 2000));
+        assert!(!evaluator.is_outdated());
+    }
+
+    #[test]
+    fn reloads_once_settled() {
+        let dir = TempDir::new("settled");
+        let model_file = File::create(dir.join("saved_model.pb")).unwrap();
+        let evaluator = ReloadableEvaluator::new(&dir, |_| Ok(UniformEvaluator)).unwrap();
+
+        // the trainer is still writing
+        model_file.set_modified(SystemTime::now()).unwrap();
+        let settle = Duration::from_secs(3600);
+        assert!(evaluator.is_outdated());
+        assert!(!evaluator.reload_if_settled(settle).unwrap());
+        assert_eq!(evaluator.generation(), 0);
+
+        model_file.set_modified(SystemTime::now() - settle).unwrap();
+        assert!(evaluator.reload_if_settled(settle).unwrap());
+        assert_eq!(evaluator.generation(), 1);
+        assert!(!evaluator.reload_if_settled(settle).unwrap());
+    }
+}
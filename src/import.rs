@@ -0,0 +1,409 @@
+//! Import of games played elsewhere as supervised training data (`cargo run -- import <files>`):
+//! Piskvork `.psq` files, like the Gomocup ones, and RIF / renju.net records, either as the XML of the
+//! renju.net database or as one move list like `h8 i9 j8` per line.
+//! The games are replayed with `GameState::move_game` and stored like self-play games, with a pi all
+//! on the move played. Games from bigger boards are moved onto the 13x13 board when they fit in it
+use std::collections::BTreeMap;
+use std::fmt::{self, Display, Formatter};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::constants::{self, sizes};
+use crate::evaluator::ModelId;
+use crate::game_record::{GameRecord, SearchSettings, TurnRecord};
+use crate::rules::types::{GameResult, GameState, Move};
+use crate::shards::{self, ShardError};
+
+// size of the board of renju.net records, which don't say it
+const RIF_BOARD_SIZE: usize = 15;
+
+/// Why a collection couldn't be imported
+#[derive(Debug)]
+pub enum ImportError {
+    Io(io::Error),
+    /// A file isn't in the format its extension says
+    Parse(String),
+    Shard(ShardError),
+}
+
+impl Display for ImportError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ImportError::Io(e) => write!(f, "can't read games: {}", e),
+            ImportError::Parse(e) => write!(f, "can't parse games: {}", e),
+            ImportError::Shard(e) => write!(f, "can't store games: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ImportError {}
+
+impl From<io::Error> for ImportError {
+    fn from(e: io::Error) -> Self {
+        ImportError::Io(e)
+    }
+}
+impl From<ShardError> for ImportError {
+    fn from(e: ShardError) -> Self {
+        ImportError::Shard(e)
+    }
+}
+
+/// A game as read from a collection
+#[derive(Clone, PartialEq, Debug)]
+pub struct ExternalGame {
+    /// Width and height of the board it was played on
+    pub board_size: (usize, usize),
+    /// `(x, y)` of the moves from the top left corner, the first one is X's
+    pub moves: Vec<(usize, usize)>,
+    /// The result given by the collection, if any
+    pub result: Option<GameResult>,
+}
+
+/// Reads a Piskvork `.psq` game: a `Piskvork <width>x<height>, ...` header,
+/// then a `x,y,time` line per move counting from 1, up to the first line that isn't a move
+pub fn parse_psq(text: &str) -> Result<ExternalGame, ImportError> {
+    let mut lines = text.lines();
+    let header = lines.next().unwrap_or_default();
+    let size = header
+        .strip_prefix("Piskvork ")
+        .and_then(|rest| rest.split(',').next())
+        .ok_or_else(|| ImportError::Parse(format!("'{}' isn't a Piskvork header", header)))?;
+    let parse_size = |n: &str| n.trim().parse::<usize>().ok();
+    let board_size = match size.split_once('x') {
+        Some((width, height)) => parse_size(width).zip(parse_size(height)),
+        None => parse_size(size).map(|n| (n, n)),
+    }
+    .ok_or_else(|| ImportError::Parse(format!("'{}' isn't a board size", size)))?;
+
+    let mut moves = Vec::new();
+    for line in lines {
+        let fields: Vec<Option<usize>> = line.split(',').map(parse_size).collect();
+        match fields[..] {
+            [Some(x), Some(y), ..] if x >= 1 && y >= 1 => moves.push((x - 1, y - 1)),
+            _ => break,
+        }
+    }
+    Ok(ExternalGame {
+        board_size,
+        moves,
+        result: None,
+    })
+}
+
+/// Reads RIF / renju.net games: the `<game>` elements of the renju.net XML, with the result for black
+/// (X here) in `bresult`, or else every line of moves like `h8 i9 j8`, columns from `a` and rows from the bottom
+pub fn parse_rif(text: &str) -> Result<Vec<ExternalGame>, ImportError> {
+    if !text.contains("<game") {
+        return text
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| rif_game(line, None))
+            .collect();
+    }
+    let mut games = Vec::new();
+    // not the `<games>` around them
+    let elements = text
+        .split("<game")
+        .skip(1)
+        .filter(|element| element.starts_with([' ', '>']));
+    for element in elements {
+        let element = element.split("</game>").next().unwrap_or_default();
+        let result = match attribute(element, "bresult") {
+            Some("1") => Some(GameResult::XWins),
+            Some("0") => Some(GameResult::OWins),
+            Some("0.5") => Some(GameResult::Draws),
+            _ => None,
+        };
+        let moves = element
+            .split_once("<move>")
+            .and_then(|(_, rest)| rest.split_once("</move>"))
+            .map_or("", |(moves, _)| moves);
+        games.push(rif_game(moves, result)?);
+    }
+    Ok(games)
+}
+
+/// The value of the attribute `name` in the start tag of `element`
+fn attribute<'a>(element: &'a str, name: &str) -> Option<&'a str> {
+    let start_tag = element.split('>').next()?;
+    let (_, rest) = start_tag.split_once(&format!(" {}=\"", name))?;
+    rest.split('"').next()
+}
+
+fn rif_game(moves: &str, result: Option<GameResult>) -> Result<ExternalGame, ImportError> {
+    let moves = moves
+        .split_whitespace()
+        .map(|token| {
+            let mut chars = token.chars();
+            let column = chars.next().filter(char::is_ascii_lowercase);
+            let row = chars.as_str().parse::<usize>().ok().filter(|&row| row >= 1);
+            match column.zip(row) {
+                Some((column, row)) if row <= RIF_BOARD_SIZE => {
+                    Ok((column as usize - 'a' as usize, RIF_BOARD_SIZE - row))
+                }
+                _ => Err(ImportError::Parse(format!("'{}' isn't a move", token))),
+            }
+        })
+        .collect::<Result<_, _>>()?;
+    Ok(ExternalGame {
+        board_size: (RIF_BOARD_SIZE, RIF_BOARD_SIZE),
+        moves,
+        result,
+    })
+}
+
+/// Why a game wasn't imported
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Skip {
+    /// The moves spread over more than the 13x13 board
+    TooBig,
+    /// A move is off the board or on a stone
+    IllegalMove,
+    /// The game didn't end and the collection doesn't give its result
+    NoResult,
+}
+
+impl Display for Skip {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Skip::TooBig => write!(f, "don't fit on the board"),
+            Skip::IllegalMove => write!(f, "have illegal moves"),
+            Skip::NoResult => write!(f, "have no result"),
+        }
+    }
+}
+
+/// Replays `game`, recorded as played by `source`.
+/// A game played on a board bigger than 13x13 is centered on it, if its moves fit,
+/// and a game that ends earlier under the rules used here is cut there
+pub fn to_record(game: &ExternalGame, source: &str) -> Result<GameRecord, Skip> {
+    let offset = |size: usize, coords: Vec<usize>, board: usize| -> Result<isize, Skip> {
+        let (min, max) = match (coords.iter().min(), coords.iter().max()) {
+            (Some(&min), Some(&max)) => (min, max),
+            _ => return Ok(0),
+        };
+        if size <= board {
+            Ok(0)
+        } else if max - min < board {
+            Ok(min as isize - ((board - (max - min + 1)) / 2) as isize)
+        } else {
+            Err(Skip::TooBig)
+        }
+    };
+    let (width, height) = game.board_size;
+    let dx = offset(
+        width,
+        game.moves.iter().map(|&(x, _)| x).collect(),
+        sizes::BOARD_WIDTH,
+    )?;
+    let dy = offset(
+        height,
+        game.moves.iter().map(|&(_, y)| y).collect(),
+        sizes::BOARD_HEIGHT,
+    )?;
+
+    let mut record = GameRecord::new(
+        ModelId::new(source, 0),
+        // nothing was searched, the pi of the played move is 1
+        SearchSettings {
+            num_search: 0,
+            c_puct: 0.0,
+            exploration: 1.0,
+            play_stochastically: true,
+        },
+        0,
+    );
+    let mut game_state = GameState::init_game_state();
+    for &(x, y) in &game.moves {
+        if game_state.evaluate().has_ended() {
+            break;
+        }
+        let (x, y) = (x as isize - dx, y as isize - dy);
+        if !(0..sizes::BOARD_WIDTH as isize).contains(&x)
+            || !(0..sizes::BOARD_HEIGHT as isize).contains(&y)
+        {
+            return Err(Skip::IllegalMove);
+        }
+        let mv = Move::new(x as usize, y as usize);
+        if !game_state.get_legal_moves(None).contains(&mv) {
+            return Err(Skip::IllegalMove);
+        }
+        record.turns.push(TurnRecord {
+            mv,
+            root_value: 0.0,
+            visits: vec![[mv.x, mv.y, 1]],
+            time_ms: 0,
+        });
+        game_state.move_game(mv, None);
+    }
+    let result = match game_state.evaluate() {
+        GameResult::NotFinished => game.result.ok_or(Skip::NoResult)?,
+        result => result,
+    };
+    if record.turns.is_empty() {
+        return Err(Skip::NoResult);
+    }
+    // without a search, the best guess of the value is the outcome
+    let positions = record.positions();
+    for (turn, position) in record.turns.iter_mut().zip(positions) {
+        turn.root_value = result.outcome_for_side(position.get_side());
+    }
+    record.finish(result);
+    Ok(record)
+}
+
+/// What `import_files` did
+#[derive(Default, PartialEq, Debug)]
+pub struct ImportSummary {
+    pub games: usize,
+    pub positions: usize,
+    pub skipped: BTreeMap<Skip, usize>,
+}
+
+impl Display for ImportSummary {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Imported {} games, {} positions",
+            self.games, self.positions
+        )?;
+        for (skip, count) in &self.skipped {
+            write!(f, ", skipped {} that {}", count, skip)?;
+        }
+        Ok(())
+    }
+}
+
+/// Imports the games of `paths` into new shards of the data directory `dir`.
+/// `.psq` files hold a Piskvork game, any other file RIF / renju.net games
+pub fn import_files(paths: &[PathBuf], dir: &Path) -> Result<ImportSummary, ImportError> {
+    let mut summary = ImportSummary::default();
+    let mut records = Vec::new();
+    for path in paths {
+        let text = fs::read_to_string(path)?;
+        let in_file = |e: ImportError| match e {
+            ImportError::Parse(e) => ImportError::Parse(format!("{}: {}", path.display(), e)),
+            e => e,
+        };
+        let (games, source) = if path.extension().is_some_and(|ext| ext == "psq") {
+            (vec![parse_psq(&text).map_err(in_file)?], "import:psq")
+        } else {
+            (parse_rif(&text).map_err(in_file)?, "import:rif")
+        };
+        for game in games {
+            match to_record(&game, source) {
+                Ok(record) => {
+                    summary.games += 1;
+                    summary.positions += record.turns.len();
+                    records.push(record);
+                }
+                Err(skip) => *summary.skipped.entry(skip).or_default() += 1,
+            }
+        }
+    }
+    for chunk in records.chunks(constants::NUM_GAME_PER_SHARD) {
+        shards::write_shard(dir, chunk)?;
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::TempDir;
+    use crate::types::ReplayData;
+    use crate::validate;
+
+    /// X plays on the row `y`, O just below, X gets five first
+    fn psq(size: usize, y: usize) -> String {
+        let mut text = format!("Piskvork {size}x{size}, 11:11, 0\n");
+        for x in 1..=5 {
+            text.push_str(&format!("{},{},100\n", x + 10, y));
+            if x < 5 {
+                text.push_str(&format!("{},{},100\n", x + 10, y + 1));
+            }
+        }
+        text.push_str("-1\npbrain-one.exe\n");
+        text
+    }
+
+    #[test]
+    fn parse_test() {
+        let game = parse_psq(&psq(20, 3)).unwrap();
+        assert_eq!(game.board_size, (20, 20));
+        assert_eq!(game.moves.len(), 9);
+        assert_eq!(game.moves[1], (10, 3));
+        assert!(parse_psq("not a game").is_err());
+
+        let xml = r#"<games><game id="1" bid="2" bresult="0" btime="0"><move>h8 i9 a1</move></game>
+            <game id="2" bresult="0.5"><move></move></game></games>"#;
+        let games = parse_rif(xml).unwrap();
+        assert_eq!(games.len(), 2);
+        assert_eq!(games[0].moves, vec![(7, 7), (8, 6), (0, 14)]);
+        assert_eq!(games[0].result, Some(GameResult::OWins));
+        assert_eq!(games[1].result, Some(GameResult::Draws));
+        assert_eq!(parse_rif("h8 h9\n\nj10\n").unwrap().len(), 2);
+        assert!(parse_rif("h8 zz").is_err());
+    }
+
+    #[test]
+    fn to_record_test() {
+        // moved from the far right of a 20x20 board, and X wins with its fifth stone
+        let record = to_record(&parse_psq(&psq(20, 18)).unwrap(), "test").unwrap();
+        assert_eq!(record.result, GameResult::XWins);
+        assert_eq!(record.turns.len(), 9);
+        assert_eq!(record.turns[0].mv, Move::new(4, 5));
+        assert_eq!(record.turns[0].root_value, 1.0);
+        assert_eq!(record.turns[1].root_value, -1.0);
+
+        let game = |moves: Vec<(usize, usize)>, result| ExternalGame {
+            board_size: (15, 15),
+            moves,
+            result,
+        };
+        assert_eq!(
+            to_record(&game(vec![(0, 0), (14, 14)], None), "test"),
+            Err(Skip::TooBig)
+        );
+        assert_eq!(
+            to_record(&game(vec![(3, 3), (3, 3)], None), "test"),
+            Err(Skip::IllegalMove)
+        );
+        assert_eq!(
+            to_record(&game(vec![(3, 3), (4, 4)], None), "test"),
+            Err(Skip::NoResult)
+        );
+        // a game of a 13x13 board stays where it was played
+        let small = ExternalGame {
+            board_size: (13, 13),
+            moves: vec![(3, 3)],
+            result: Some(GameResult::OWins),
+        };
+        let record = to_record(&small, "test").unwrap();
+        assert_eq!(record.turns[0].mv, Move::new(3, 3));
+        assert_eq!(record.result, GameResult::OWins);
+    }
+
+    #[test]
+    fn import_files_test() {
+        let dir = TempDir::new("import");
+        let psq_path = dir.join("game.psq");
+        fs::write(&psq_path, psq(15, 7)).unwrap();
+        let rif_path = dir.join("games.txt");
+        fs::write(&rif_path, "h8 i9\na1 o15\n").unwrap();
+        let data_dir = dir.join("training_data");
+
+        let summary = import_files(&[psq_path, rif_path], &data_dir).unwrap();
+        assert_eq!((summary.games, summary.positions), (1, 9));
+        assert_eq!(
+            summary.skipped,
+            BTreeMap::from([(Skip::TooBig, 1), (Skip::NoResult, 1)])
+        );
+        let data = ReplayData::load(&data_dir).unwrap();
+        assert_eq!(data.len(), 9);
+        assert_eq!(data.pis.sum(), 9.0);
+        assert!(validate::validate(&data_dir).unwrap().is_ok());
+    }
+}
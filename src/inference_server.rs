@@ -0,0 +1,289 @@
+//! Lets many threads share one evaluator, evaluating their positions in batches
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::constants;
+use crate::evaluator::{Evaluation, Evaluator, ModelId};
+use crate::net::NetError;
+use crate::rules::types::GameState;
+
+/// When the server stops waiting for more requests and evaluates the batch
+#[derive(Clone, Copy, Debug)]
+pub struct BatchPolicy {
+    pub max_batch: usize,
+    /// How long to wait for the batch to fill up after the first request arrives
+    pub max_wait: Duration,
+}
+
+impl Default for BatchPolicy {
+    fn default() -> Self {
+        BatchPolicy {
+            max_batch: constants::inference::MAX_BATCH,
+            max_wait: Duration::from_millis(constants::inference::MAX_WAIT_MS),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct InferenceStats {
+    pub num_requests: usize,
+    pub num_batches: usize,
+}
+
+impl InferenceStats {
+    pub fn mean_batch_size(&self) -> f32 {
+        if self.num_batches == 0 {
+            0.0
+        } else {
+            self.num_requests as f32 / self.num_batches as f32
+        }
+    }
+}
+
+type Reply = Result<Evaluation, NetError>;
+
+struct Request {
+    game_state: GameState,
+    reply_tx: Sender<Reply>,
+}
+
+/// Thread that owns the evaluator. Runs until every `InferenceClient` is dropped
+pub struct InferenceServer {
+    handle: JoinHandle<InferenceStats>,
+}
+
+impl InferenceServer {
+    /// Starts the server thread and returns it with a client,
+    /// clone the client to give one to every worker
+    pub fn spawn<E>(evaluator: Arc<E>, policy: BatchPolicy) -> (InferenceServer, InferenceClient)
+    where
+        E: Evaluator + Send + Sync + ?Sized + 'static,
+    {
+        let (request_tx, request_rx) = mpsc::channel();
+        let model: Arc<dyn Fn() -> (ModelId, u64) + Send + Sync> = {
+            let evaluator = Arc::clone(&evaluator);
+            Arc::new(move || (evaluator.model_id(), evaluator.generation()))
+        };
+        let handle = thread::spawn(move || serve(evaluator.as_ref(), request_rx, policy));
+        (
+            InferenceServer { handle },
+            InferenceClient::new(request_tx, model),
+        )
+    }
+
+    /// Waits for the server to finish, which happens after all the clients are dropped
+    pub fn join(self) -> InferenceStats {
+        self.handle.join().expect("Inference server panicked")
+    }
+}
+
+fn serve<E: Evaluator + ?Sized>(
+    evaluator: &E,
+    request_rx: Receiver<Request>,
+    policy: BatchPolicy,
+) -> InferenceStats {
+    let mut stats = InferenceStats::default();
+    // blocks until a request comes, stops when all clients are gone
+    while let Ok(first) = request_rx.recv() {
+        let deadline = Instant::now() + policy.max_wait;
+        let mut batch = vec![first];
+        while batch.len() < policy.max_batch {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            match request_rx.recv_timeout(deadline - now) {
+                Ok(request) => batch.push(request),
+                Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+
+        let (game_states, reply_txs): (Vec<GameState>, Vec<Sender<Reply>>) = batch
+            .into_iter()
+            .map(|request| (request.game_state, request.reply_tx))
+            .unzip();
+        // a failed batch fails every request in it, the server keeps serving the next ones
+        let replies: Vec<Reply> = match evaluator.try_evaluate_batch(&game_states) {
+            Ok(evaluations) => evaluations.into_iter().map(Ok).collect(),
+            Err(e) => vec![Err(e); game_states.len()],
+        };
+        for (reply_tx, reply) in reply_txs.into_iter().zip(replies) {
+            // the client might have given up, nothing to do about it
+            let _ = reply_tx.send(reply);
+        }
+        stats.num_requests += game_states.len();
+        stats.num_batches += 1;
+    }
+    stats
+}
+
+/// Sends positions to an `InferenceServer` and waits for the results.
+/// Meant to be used by a single thread, clone it for the others
+pub struct InferenceClient {
+    request_tx: Sender<Request>,
+    /// asks the server's evaluator which model it runs, and its generation
+    model: Arc<dyn Fn() -> (ModelId, u64) + Send + Sync>,
+    reply_tx: Sender<Reply>,
+    reply_rx: Receiver<Reply>,
+}
+
+impl InferenceClient {
+    fn new(
+        request_tx: Sender<Request>,
+        model: Arc<dyn Fn() -> (ModelId, u64) + Send + Sync>,
+    ) -> Self {
+        let (reply_tx, reply_rx) = mpsc::channel();
+        InferenceClient {
+            request_tx,
+            model,
+            reply_tx,
+            reply_rx,
+        }
+    }
+}
+
+impl Clone for InferenceClient {
+    fn clone(&self) -> Self {
+        InferenceClient::new(self.request_tx.clone(), Arc::clone(&self.model))
+    }
+}
+
+impl Evaluator for InferenceClient {
+    fn evaluate(&self, game_state: &GameState) -> Evaluation {
+        self.evaluate_batch(std::slice::from_ref(game_state))
+            .remove(0)
+    }
+    fn evaluate_batch(&self, game_states: &[GameState]) -> Vec<Evaluation> {
+        self.try_evaluate_batch(game_states)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+    /// Sends all the positions before waiting, so they can go in the same batch
+    fn try_evaluate_batch(&self, game_states: &[GameState]) -> Result<Vec<Evaluation>, NetError> {
+        for game_state in game_states {
+            self.request_tx
+                .send(Request {
+                    game_state: game_state.clone(),
+                    reply_tx: self.reply_tx.clone(),
+                })
+                .expect("Inference server stopped");
+        }
+        // every reply is received, even after an error, so none is left for the next call
+        let replies: Vec<Reply> = game_states
+            .iter()
+            .map(|_| self.reply_rx.recv().expect("Inference server stopped"))
+            .collect();
+        replies.into_iter().collect()
+    }
+    fn generation(&self) -> u64 {
+        (self.model)().1
+    }
+    fn model_id(&self) -> ModelId {
+        (self.model)().0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::evaluator::UniformEvaluator;
+    use crate::rules::types::Move;
+    use std::sync::Mutex;
+
+    /// Remembers the size of every batch it gets
+    struct RecordingEvaluator {
+        batch_sizes: Mutex<Vec<usize>>,
+    }
+    impl Evaluator for RecordingEvaluator {
+        fn evaluate(&self, game_state: &GameState) -> Evaluation {
+            self.evaluate_batch(std::slice::from_ref(game_state))
+                .remove(0)
+        }
+        fn evaluate_batch(&self, game_states: &[GameState]) -> Vec<Evaluation> {
+            self.batch_sizes.lock().unwrap().push(game_states.len());
+            UniformEvaluator.evaluate_batch(game_states)
+        }
+    }
+
+    /// Fails on every position with a stone on the board
+    struct FailingEvaluator;
+    impl Evaluator for FailingEvaluator {
+        fn evaluate(&self, game_state: &GameState) -> Evaluation {
+            UniformEvaluator.evaluate(game_state)
+        }
+        fn try_evaluate_batch(
+            &self,
+            game_states: &[GameState],
+        ) -> Result<Vec<Evaluation>, NetError> {
+            if game_states
+                .iter()
+                .any(|gs| gs.clone().into_iter().any(|b| b))
+            {
+                return Err(NetError::Run("stones on the board".into()));
+            }
+            Ok(self.evaluate_batch(game_states))
+        }
+    }
+
+    #[test]
+    fn batches_requests_from_threads() {
+        let evaluator = Arc::new(RecordingEvaluator {
+            batch_sizes: Mutex::new(Vec::new()),
+        });
+        let policy = BatchPolicy {
+            max_batch: 4,
+            max_wait: Duration::from_millis(50),
+        };
+        let (server, client) = InferenceServer::spawn(Arc::clone(&evaluator), policy);
+        assert_eq!(client.model_id(), evaluator.model_id());
+
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let client = client.clone();
+                thread::spawn(move || {
+                    for _ in 0..5 {
+                        let evaluation = client.evaluate(&GameState::init_game_state());
+                        assert_eq!(evaluation.value, 0.0);
+                    }
+                })
+            })
+            .collect();
+        drop(client);
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        let stats = server.join();
+
+        let batch_sizes = evaluator.batch_sizes.lock().unwrap();
+        assert_eq!(stats.num_requests, 8 * 5);
+        assert_eq!(batch_sizes.iter().sum::<usize>(), 8 * 5);
+        assert_eq!(stats.num_batches, batch_sizes.len());
+        assert!(batch_sizes.iter().all(|&size| size <= 4));
+        assert!(stats.mean_batch_size() > 1.0);
+    }
+
+    #[test]
+    fn reports_errors_to_clients() {
+        let (server, client) = InferenceServer::spawn(
+            Arc::new(FailingEvaluator),
+            BatchPolicy {
+                max_batch: 1,
+                max_wait: Duration::ZERO,
+            },
+        );
+        let mut game = GameState::init_game_state();
+        game.move_game(Move::new(0, 0), None);
+        assert!(matches!(
+            client.try_evaluate_batch(&[game]),
+            Err(NetError::Run(_))
+        ));
+        // the server keeps serving after the error
+        let evaluations = client
+            .try_evaluate_batch(&[GameState::init_game_state()])
+            .unwrap();
+        assert_eq!(evaluations.len(), 1);
+        drop(client);
+        assert_eq!(server.join().num_batches, 2);
+    }
+}
@@ -1,11 +1,38 @@
 pub mod monte_carlo_tree_search;
 
+pub mod compact;
+
 pub mod constants;
 
+pub mod evaluation_cache;
+
+pub mod evaluator;
+
+pub mod game_record;
+
+pub mod hot_reload;
+
+pub mod import;
+
+pub mod inference_server;
+
+pub mod net;
+
+pub mod replay_buffer;
+
 pub mod rules;
 
+pub mod shards;
+
+pub mod stats;
+
+#[cfg(test)]
+pub(crate) mod test_util;
+
 pub mod types;
 
+pub mod validate;
+
 #[cfg(test)]
 mod test {
     use crate::rules::types::*;
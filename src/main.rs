@@ -1,27 +1,41 @@
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
 use lib::constants;
+use lib::evaluation_cache::CachedEvaluator;
+use lib::evaluator::{
+    Evaluator, HeuristicEvaluator, SymmetricEvaluator, SymmetryMode, UniformEvaluator,
+};
+use lib::game_record::{GameRecord, SearchSettings};
+use lib::hot_reload::ReloadableEvaluator;
+use lib::import;
+use lib::inference_server::{BatchPolicy, InferenceClient, InferenceServer};
 use lib::monte_carlo_tree_search::TreeSearch;
+use lib::net::native::NativeNet;
+#[cfg(feature = "onnx")]
+use lib::net::onnx::OnnxNet;
+#[cfg(feature = "tensorflow")]
+use lib::net::tensorflow::NeuralNet;
+#[cfg(feature = "tensorflow")]
+use lib::net::training::{append_loss_history, Trainer, TrainingConfig};
+use lib::replay_buffer::ReplayBuffer;
 use lib::rules;
 use lib::rules::types::GameState;
-use lib::rules::types::NeuralNet;
-use lib::types::TrainingData;
-use ndarray_npy::WriteNpyError;
+use lib::shards::{self, ShardError};
+use lib::stats::{merge_duplicates, DataStats};
+use lib::types::{ReplayData, TrainingData};
+use lib::validate;
 
 use std::error::Error;
-use std::fs::OpenOptions;
+use std::fs::{self, OpenOptions};
 use std::io;
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::mpsc;
 use std::sync::mpsc::Receiver;
 use std::sync::mpsc::Sender;
 use std::sync::Arc;
 use std::sync::Barrier;
 use std::thread;
-
-use tensorflow;
-use tensorflow::Code;
-use tensorflow::Status;
+use std::time::Duration;
 
 /// Struct that hold information of the log to send to the logging thread (logger),
 /// which will log the text into LOG_PATH
@@ -42,11 +56,7 @@ fn logger(log_rx: Receiver<LogText>) -> io::Result<()> {
         let path: PathBuf = [constants::LOG_PATH.to_owned(), format!("thread #{i}.txt")]
             .iter()
             .collect();
-        let mut file = OpenOptions::new()
-            .append(true)
-            .create(true)
-            .open(path)
-            .unwrap();
+        let mut file = OpenOptions::new().append(true).create(true).open(path)?;
         writeln!(file, "--New session starts--")?;
         log_files.push(file);
     }
@@ -83,8 +93,8 @@ fn progress_printer(
     // initializes the progress bars
     let mp = MultiProgress::new();
     let mut pbars = Vec::with_capacity(constants::NUM_THREADS);
-    for i in 0..constants::NUM_THREADS {
-        let bar = mp.add(ProgressBar::new(num_games[i] as u64));
+    for (i, &num_game) in num_games.iter().enumerate() {
+        let bar = mp.add(ProgressBar::new(num_game as u64));
         let style = ProgressStyle::default_bar()
         .template(&format!("Thread #{i} [{{elapsed_precise}}] {{bar:40.cyan/blue}} Game #{{pos:4}}/{{len:5}} {{msg}}")[..]);
         bar.set_style(style);
@@ -95,7 +105,7 @@ fn progress_printer(
     let bc = Arc::clone(&barrier);
     // spawns a new thread that updates all the progress bars
     let _ = thread::spawn(move || {
-        let mut move_numbers = vec![0; constants::NUM_THREADS];
+        let mut move_numbers = [0; constants::NUM_THREADS];
         let mut flag = false; // indicate we waited for barrier
         loop {
             match progress_rx.recv() {
@@ -130,34 +140,54 @@ fn progress_printer(
     Ok(())
 }
 
-// Dumps data when recieved one
-fn dumper(data_rx: Receiver<TrainingData>) -> Result<(), WriteNpyError> {
+// Collects the games it receives and dumps them to a new shard every `NUM_GAME_PER_SHARD` games
+fn dumper(data_rx: Receiver<TrainingData>) -> Result<(), ShardError> {
+    let mut shard = TrainingData::new();
+    let mut num_games = 0;
     loop {
         match data_rx.recv() {
-            Ok(data) => data.dump()?,
-            Err(_) => return Ok(()),
+            Ok(data) => {
+                shard.append_game(data);
+                num_games += 1;
+                if num_games == constants::NUM_GAME_PER_SHARD {
+                    std::mem::take(&mut shard).dump()?;
+                    num_games = 0;
+                }
+            }
+            Err(_) => {
+                if num_games > 0 {
+                    shard.dump()?;
+                }
+                return Ok(());
+            }
         }
     }
 }
 
 /// Generates the games for the training datas
-/// `thread_number` is the thread "id" this function is in
+/// `thread_number` is the thread "id" this function is in.
+/// With `watched`, a newly trained model is swapped in between games
 ///
 fn generate_games(
     num_game: usize,
-    net: Arc<NeuralNet>,
+    net: InferenceClient,
+    watched: Option<Arc<ReloadableEvaluator<DynEvaluator>>>,
     log_tx: Sender<LogText>,
     data_tx: Sender<TrainingData>,
     progress_tx: Sender<ProgressSignal>,
     thread_number: usize,
 ) {
     for g in 0..num_game {
+        if let Some(watched) = &watched {
+            swap_in_new_model(watched, &log_tx, thread_number);
+        }
         // log start of game
         log_tx
             .send(LogText {
                 text: format!(
-                    "Generating game number {g}/{}\n",
-                    constants::NUM_GAME_PER_STEP
+                    "Generating game number {g}/{} with {}\n",
+                    constants::NUM_GAME_PER_STEP,
+                    net.model_id()
                 ),
                 channel: thread_number,
             })
@@ -166,7 +196,11 @@ fn generate_games(
         let mut game_state = GameState::init_game_state();
         let mut tree_search = TreeSearch::new(game_state.clone());
         let mut res = game_state.evaluate();
-        let mut training_data = TrainingData::new();
+        let mut record = GameRecord::new(
+            net.model_id(),
+            SearchSettings::current(true),
+            tree_search.seed(),
+        );
 
         // game loop
         while !res.has_ended() {
@@ -178,8 +212,8 @@ fn generate_games(
             // get output from tree search
             let tree_search_output = tree_search.search(&net, true);
 
-            // add this turn to the training data
-            training_data.append_turn(&game_state, &tree_search_output.pi);
+            // add this turn to the record of the game, which the training data is made from
+            record.push_turn(&tree_search_output);
 
             // move the game based on teh tree search output
             let best_move = tree_search_output.best_move;
@@ -194,8 +228,10 @@ fn generate_games(
 
             res = game_state.evaluate();
         }
-        // uodate the game result to all the moves data after finishing the game
-        training_data.set_result(res);
+        // the result gives the outcome of every position of the game
+        record.finish(res);
+        let mut training_data = TrainingData::new();
+        training_data.add_record(record);
 
         // log that we finish the game
         log_tx
@@ -205,8 +241,10 @@ fn generate_games(
             })
             .unwrap();
 
-        // send the data to be dumped
-        data_tx.send(training_data).unwrap();
+        // send the data to be dumped, the dumper only hangs up when it failed, which main reports
+        if data_tx.send(training_data).is_err() {
+            return;
+        }
         // resets progress bar
         progress_tx
             .send(ProgressSignal::New(thread_number))
@@ -218,31 +256,125 @@ fn generate_games(
         .unwrap();
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
-    // checks if constants are valid
-    rules::vaildate_consts()?;
-    // update constants.jsonc for scripts
-    constants::write_constants_to_file()?;
+/// Value given to the command line option `option`, if it's there
+fn arg_value(option: &str) -> Result<Option<String>, Box<dyn Error>> {
+    let args: Vec<String> = std::env::args().collect();
+    match args.iter().position(|arg| arg == option) {
+        Some(i) => Ok(Some(
+            args.get(i + 1)
+                .ok_or(format!("{option} needs a value"))?
+                .clone(),
+        )),
+        None => Ok(None),
+    }
+}
 
-    let model_file: PathBuf = [constants::model::NET_PATH, "saved_model.pb"]
-        .iter()
-        .collect();
-    // heck if the model is there
-    if !model_file.exists() {
-        return Err(Box::new(
-            Status::new_set(
-                Code::NotFound,
-                &format!(
-                    "Run 'python scripts/init_models.py' to generate {} and try again.",
-                    model_file.display()
-                ),
-            )
-            .unwrap(),
-        ));
+/// Whether the command line flag `flag` was given
+fn has_flag(flag: &str) -> bool {
+    std::env::args().any(|arg| arg == flag)
+}
+
+type DynEvaluator = Arc<dyn Evaluator + Send + Sync>;
+type CachedNet = Arc<CachedEvaluator<DynEvaluator>>;
+type Evaluators = (
+    DynEvaluator,
+    CachedNet,
+    Option<Arc<ReloadableEvaluator<DynEvaluator>>>,
+);
+
+/// Loads the network at `path` with `backend`, called `name` if given
+fn load_net(
+    backend: &str,
+    path: &Path,
+    name: Option<&str>,
+) -> Result<DynEvaluator, Box<dyn Error>> {
+    match backend {
+        #[cfg(feature = "tensorflow")]
+        "net" => Ok(Arc::new(match name {
+            Some(name) => NeuralNet::load_named(path, name)?,
+            None => NeuralNet::load(path)?,
+        })),
+        #[cfg(feature = "onnx")]
+        "onnx" => Ok(Arc::new(match name {
+            Some(name) => OnnxNet::load_named(path, name)?,
+            None => OnnxNet::load(path)?,
+        })),
+        "native" => Ok(Arc::new(match name {
+            Some(name) => NativeNet::load_named(path, name)?,
+            None => NativeNet::load(path)?,
+        })),
+        _ => Err(format!("The {backend} backend isn't enabled in this build").into()),
     }
+}
 
-    // load the network
-    let net = Arc::new(NeuralNet::new());
+/// Loads the evaluator that guides the self-play games,
+/// chosen with `--evaluator <net|onnx|native|heuristic|uniform>`
+/// (defaults to net, or onnx when built without TensorFlow).
+/// The net is loaded from `--model <path>` (defaults to `model::NET_PATH`, `ONNX_PATH` or `NATIVE_PATH`),
+/// and can be renamed with `--model-name <name>`.
+/// The cache sits below the symmetries, so it holds what the net said about the transformed positions.
+/// Also returns the cache, for its stats, and the net on its own, so it can be reloaded
+fn load_evaluator() -> Result<Evaluators, Box<dyn Error>> {
+    let default_backend = if cfg!(feature = "tensorflow") {
+        "net"
+    } else {
+        "onnx"
+    };
+    let name = arg_value("--evaluator")?.unwrap_or_else(|| default_backend.to_string());
+    let mut reloadable = None;
+    let evaluator: DynEvaluator = match name.as_str() {
+        "net" | "onnx" | "native" => {
+            let default_path = match name.as_str() {
+                "net" => constants::model::NET_PATH,
+                "onnx" => constants::model::ONNX_PATH,
+                _ => constants::model::NATIVE_PATH,
+            };
+            let path = arg_value("--model")?.unwrap_or_else(|| default_path.into());
+            let model_name = arg_value("--model-name")?;
+            let net = Arc::new(ReloadableEvaluator::new(Path::new(&path), move |path| {
+                load_net(&name, path, model_name.as_deref())
+            })?);
+            reloadable = Some(Arc::clone(&net));
+            net
+        }
+        "heuristic" => Arc::new(HeuristicEvaluator),
+        "uniform" => Arc::new(UniformEvaluator),
+        _ => {
+            return Err(format!(
+                "Unknown evaluator '{name}', expected net, onnx, native, heuristic or uniform"
+            )
+            .into())
+        }
+    };
+    // evaluate through the symmetries of the board if asked
+    let mode = match arg_value("--symmetry")? {
+        Some(name) => SymmetryMode::from_name(&name).ok_or(format!(
+            "Unknown symmetry mode '{name}', expected none, random or average"
+        ))?,
+        None => constants::inference::SYMMETRY_MODE,
+    };
+    let cache = Arc::new(CachedEvaluator::new(
+        evaluator,
+        constants::inference::CACHE_CAPACITY,
+    ));
+    Ok((
+        Arc::new(SymmetricEvaluator::new(Arc::clone(&cache), mode)),
+        cache,
+        reloadable,
+    ))
+}
+
+/// Generates `constants::NUM_GAME_PER_STEP` games with `net` and dumps them.
+/// `cache` is the evaluation cache under `net`, and `watched` the model to keep up to date between games, if any
+fn self_play_step(
+    net: &DynEvaluator,
+    cache: &CachedNet,
+    watched: Option<&Arc<ReloadableEvaluator<DynEvaluator>>>,
+) -> Result<(), Box<dyn Error>> {
+    println!("Generating games with {}", net.model_id());
+    // all the threads share the evaluator through the inference server
+    let (inference_server, inference_client) =
+        InferenceServer::spawn(Arc::clone(net), BatchPolicy::default());
 
     // signal transmiters and receivers
     let (log_tx, log_rx) = mpsc::channel();
@@ -259,33 +391,267 @@ fn main() -> Result<(), Box<dyn Error>> {
         .iter_mut()
         .for_each(|x| *x += 1);
     // spawn the threads
-    for i in 0..constants::NUM_THREADS {
+    for (i, &num_game) in num_games.iter().enumerate() {
         let ltx = log_tx.clone();
         let dtx = data_tx.clone();
         let ptx = progress_tx.clone();
-        let net_ref = Arc::clone(&net);
+        let net_ref = inference_client.clone();
+        let watched = watched.cloned();
 
-        let handle = thread::spawn(move || generate_games(num_games[i], net_ref, ltx, dtx, ptx, i));
+        let handle =
+            thread::spawn(move || generate_games(num_game, net_ref, watched, ltx, dtx, ptx, i));
         handles.push(handle);
     }
     // we don't ned the transmitter anymore in this thread (because we cloned it above)
     drop(log_tx);
     drop(data_tx);
     drop(progress_tx);
-    
+    drop(inference_client);
+
     // receiver threads
     let logger_handle = thread::spawn(move || logger(log_rx));
     let dumper_handle = thread::spawn(move || dumper(data_rx));
     let progress_handle = thread::spawn(move || progress_printer(progress_rx, num_games));
 
     // wait for everything to finish
-    for handle in handles {
-        handle.join().unwrap();
-    }
+    let generated: Vec<_> = handles.into_iter().map(|handle| handle.join()).collect();
     logger_handle.join().unwrap()?;
     dumper_handle.join().unwrap()?;
     progress_handle.join().unwrap()?;
-    
+    if generated.iter().any(Result::is_err) {
+        return Err("A thread generating games panicked".into());
+    }
+    let inference_stats = inference_server.join();
+
+    println!(
+        "Evaluated {} positions in {} batches (mean batch size {:.2})",
+        inference_stats.num_requests,
+        inference_stats.num_batches,
+        inference_stats.mean_batch_size()
+    );
+    let cache_stats = cache.stats();
+    println!(
+        "Evaluation cache: {} hits, {} misses (hit rate {:.1}%)",
+        cache_stats.hits,
+        cache_stats.misses,
+        cache_stats.hit_rate() * 100.0
+    );
+    Ok(())
+}
+
+/// Swaps in the model of the watched directory if a new one is done being written,
+/// keeping the old one if the new one can't be loaded
+fn swap_in_new_model(
+    net: &ReloadableEvaluator<DynEvaluator>,
+    log_tx: &Sender<LogText>,
+    thread_number: usize,
+) {
+    let settle = Duration::from_millis(constants::model::RELOAD_SETTLE_MS);
+    let text = match net.reload_if_settled(settle) {
+        Ok(true) => format!("Swapped in {}\n", net.model_id()),
+        Ok(false) => return,
+        Err(e) => format!(
+            "Can't load the new model, keeping {}: {}\n",
+            net.model_id(),
+            e
+        ),
+    };
+    log_tx
+        .send(LogText {
+            text,
+            channel: thread_number,
+        })
+        .unwrap();
+}
+
+/// Trains the model at `--model` (defaults to `model::NET_PATH`) on the games in `TRAINING_DATA_PATH`,
+/// then checkpoints it and appends the losses to `LOSS_HISTORY_PATH`.
+/// `--q-weight <w>` overrides `VALUE_TARGET_Q_WEIGHT`
+#[cfg(feature = "tensorflow")]
+fn train() -> Result<(), Box<dyn Error>> {
+    use constants::model::training;
+
+    let path = arg_value("--model")?.unwrap_or_else(|| constants::model::NET_PATH.into());
+    let data = sample_replay()?;
+    let mut trainer = Trainer::load(&path)?;
+    let mut config = TrainingConfig::default();
+    if let Some(q_weight) = arg_value("--q-weight")? {
+        config.q_weight = q_weight
+            .parse()
+            .ok()
+            .filter(|w| (0.0..=1.0).contains(w))
+            .ok_or(format!(
+                "--q-weight is {q_weight}, but has to be between 0 and 1"
+            ))?;
+    }
+    println!(
+        "Training {path} from step {} on {} positions",
+        trainer.step(),
+        data.len()
+    );
+
+    let num_samples = if config.augment { 8 } else { 1 } * data.len();
+    let bar = ProgressBar::new((config.epochs * num_samples.div_ceil(config.batch_size)) as u64);
+    bar.set_style(
+        ProgressStyle::default_bar()
+            .template("[{elapsed_precise}] {bar:40.cyan/blue} Step #{pos}/{len} {msg}"),
+    );
+    let history = trainer.train(&data, &config, |record| {
+        bar.inc(1);
+        bar.set_message(format!("loss {:.4}", record.loss));
+    })?;
+    bar.finish();
+
+    let checkpoint = trainer.checkpoint()?;
+    append_loss_history(training::LOSS_HISTORY_PATH, &history)?;
+    println!("Saved {}", checkpoint.display());
+    Ok(())
+}
+
+#[cfg(not(feature = "tensorflow"))]
+fn train() -> Result<(), Box<dyn Error>> {
+    Err("Training needs the tensorflow feature".into())
+}
+
+/// Samples the positions to train on from the replay window of the training data
+fn sample_replay() -> Result<ReplayData, Box<dyn Error>> {
+    let buffer = ReplayBuffer::open(
+        constants::TRAINING_DATA_PATH,
+        constants::replay::WINDOW,
+        constants::replay::SAMPLING,
+    )?;
+    if buffer.window_len() == 0 {
+        return Err("No training data, generate some games first".into());
+    }
+    println!(
+        "Sampling from the last {} of {} games ({} positions)",
+        buffer.window_games_len(),
+        buffer.num_games(),
+        buffer.window_len()
+    );
+    Ok(buffer.sample(
+        constants::model::training::MAX_SAMPLE_BOARD_FOR_TRAINING,
+        &mut rand::thread_rng(),
+    )?)
+}
+
+/// Writes a sampled training batch to `--out` (`constants::replay::BATCH_PATH` by default),
+/// for `scripts/model_trainer.py`. With `--shard <file>`, writes every position of that shard instead,
+/// expanded to dense arrays for `scripts/training_data.py`
+fn export_batch() -> Result<(), Box<dyn Error>> {
+    let path = arg_value("--out")?.unwrap_or_else(|| constants::replay::BATCH_PATH.into());
+    let data = match arg_value("--shard")? {
+        Some(file) => read_shard_file(Path::new(&file))?,
+        None => sample_replay()?,
+    };
+    shards::write_batch(Path::new(&path), &data)?;
+    println!("Wrote {} positions to {path}", data.len());
+    Ok(())
+}
+
+/// The positions of the shard at `path`, looked up in the index of its data directory
+fn read_shard_file(path: &Path) -> Result<ReplayData, Box<dyn Error>> {
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let shard = shards::read_index(dir)?
+        .into_iter()
+        .find(|shard| shard.file == name)
+        .ok_or_else(|| format!("{} isn't in the index of its directory", path.display()))?;
+    Ok(shards::read_shard(dir, &shard)?)
+}
+
+/// Reports the duplicates, game lengths, results and policy entropies of the training data.
+/// With `--dedup <file>`, also writes the positions with their duplicates merged to `file`
+fn stats() -> Result<(), Box<dyn Error>> {
+    let dir = Path::new(constants::TRAINING_DATA_PATH);
+    let data = ReplayData::load(dir)?;
+    let mut records = Vec::new();
+    for shard in shards::read_index(dir)? {
+        records.extend(shards::read_records(dir, &shard)?);
+    }
+    print!("{}", DataStats::new(&data, &records));
+
+    if let Some(path) = arg_value("--dedup")? {
+        let merged = merge_duplicates(&data);
+        shards::write_batch(Path::new(&path), &merged)?;
+        println!("Wrote {} merged positions to {path}", merged.len());
+    }
+    Ok(())
+}
+
+/// Checks every position of the training data, fails if any has a problem
+fn validate() -> Result<(), Box<dyn Error>> {
+    let report = validate::validate(Path::new(constants::TRAINING_DATA_PATH))?;
+    print!("{}", report);
+    if report.is_ok() {
+        Ok(())
+    } else {
+        Err("The training data has problems".into())
+    }
+}
+
+/// Imports the game collections given after `import` into the training data,
+/// or into the data directory `--out`
+fn import() -> Result<(), Box<dyn Error>> {
+    let out = arg_value("--out")?;
+    let mut paths = Vec::new();
+    let mut args = std::env::args().skip(2);
+    while let Some(arg) = args.next() {
+        if arg == "--out" {
+            args.next();
+        } else {
+            paths.push(PathBuf::from(arg));
+        }
+    }
+    if paths.is_empty() {
+        return Err("Give the .psq or RIF files to import".into());
+    }
+    let dir = out.unwrap_or_else(|| constants::TRAINING_DATA_PATH.into());
+    println!("{}", import::import_files(&paths, Path::new(&dir))?);
+    Ok(())
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    // checks if constants are valid
+    rules::vaildate_consts()?;
+    // update constants.jsonc for scripts
+    constants::write_constants_to_file()?;
+
+    // create the directories of a fresh checkout, and clean up after a run that crashed while dumping games
+    shards::init(Path::new(constants::TRAINING_DATA_PATH))?;
+    fs::create_dir_all(constants::LOG_PATH)?;
+    let recovery = shards::recover(Path::new(constants::TRAINING_DATA_PATH))?;
+    if !recovery.is_clean() {
+        println!(
+            "Recovered the training data: dropped {} broken shards, removed {}",
+            recovery.dropped_shards.len(),
+            recovery.removed_files.join(", ")
+        );
+    }
+
+    // `train` trains the net on the generated games instead of generating more,
+    // `export-batch` writes the positions to train on for the python trainer,
+    // `stats` describes the training data, `validate` checks it and `import` adds games played elsewhere
+    match std::env::args().nth(1).as_deref() {
+        Some("train") => return train(),
+        Some("export-batch") => return export_batch(),
+        Some("stats") => return stats(),
+        Some("validate") => return validate(),
+        Some("import") => return import(),
+        _ => {}
+    }
+
+    // load the network (or the evaluator used instead)
+    let (net, cache, reloadable) = load_evaluator()?;
+
+    // with --watch, keep generating games, swapping in every newly trained model between games
+    let watched = reloadable.filter(|_| has_flag("--watch"));
+    loop {
+        self_play_step(&net, &cache, watched.as_ref())?;
+        if watched.is_none() {
+            break;
+        }
+    }
     println!("DONE!");
     Ok(())
 }
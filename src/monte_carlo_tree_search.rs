@@ -1,19 +1,20 @@
 use crate::constants::{self, mcts, sizes};
-use crate::rules::types::{GameState, Move, NeuralNet};
-use ndarray::{Array, Array3};
+use crate::evaluator::Evaluator;
+use crate::rules::types::{GameState, Move};
+use ndarray::Array3;
 use rand::distributions::WeightedIndex;
 use rand::prelude::Distribution;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use std::{cell::RefCell, rc::Rc};
 
-use tensorflow::eager::raw_ops;
-
 #[derive(Clone)]
 struct Node {
     game_state: GameState,
     n: usize,                         // the number of time this node is visited
     w: f32,                           // sum of value of descendants
     p: f32,          // "prior probability", or policy, or prob. that the AI chose this m
-    q: f32,          // w / n, the "mean value"
+    q: f32,          // w / n, the "mean value", for the side that played m
     m: Option<Move>, // the move that lead to this node, none if it's the root node
     children: Vec<Rc<RefCell<Node>>>, // list of children
 }
@@ -36,7 +37,7 @@ impl Node {
     }
 
     pub fn iter_children(&self) -> impl Iterator<Item = Rc<RefCell<Node>>> + '_ {
-        self.children.iter().map(|c| Rc::clone(c))
+        self.children.iter().map(Rc::clone)
     }
 
     pub fn get_child(&self, ind: usize) -> Rc<RefCell<Node>> {
@@ -46,23 +47,52 @@ impl Node {
 
 type Path = Vec<Rc<RefCell<Node>>>;
 
+/// The visit counts of the root are raised to this power to get pi
+pub fn pi_exponent(play_stochastically: bool) -> f32 {
+    if play_stochastically {
+        1.0 / mcts::EXPLORATION
+    } else {
+        10.0
+    }
+}
+
 pub struct MCTSOutput {
     pub best_move: Move,
     pub pi: Array3<f32>,
+    /// Mean value of the root after the search, for the side to move
+    pub root_value: f32,
+    /// Number of visits of every move of the root
+    pub visits: Vec<(Move, usize)>,
 }
 
 pub struct TreeSearch {
     root_node: Rc<RefCell<Node>>,
+    seed: u64,
+    rng: StdRng,
 }
 
 impl TreeSearch {
     pub fn new(game_state: GameState) -> TreeSearch {
+        TreeSearch::with_seed(game_state, rand::thread_rng().gen())
+    }
+
+    /// Same as `new`, but the moves are picked with a random generator seeded with `seed`
+    pub fn with_seed(game_state: GameState, seed: u64) -> TreeSearch {
         TreeSearch {
             root_node: Node::init_node(game_state, 0.0, None),
+            seed,
+            rng: StdRng::seed_from_u64(seed),
         }
     }
-    fn backup(path: Path, value: f32) {
-        for node in path.iter() {
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+    /// `value` is for the side to move at the leaf, the last node of `path`. Every node keeps
+    /// its value for the side that played the move leading to it, so the sign flips every ply
+    fn backup(path: Path, mut value: f32) {
+        for node in path.iter().rev() {
+            value = -value;
             let mut mut_node = node.borrow_mut();
             mut_node.w += value;
             mut_node.n += 1;
@@ -70,7 +100,11 @@ impl TreeSearch {
         }
     }
 
-    pub fn search(&mut self, net: &NeuralNet, play_stochastically: bool) -> MCTSOutput {
+    pub fn search<E: Evaluator + ?Sized>(
+        &mut self,
+        evaluator: &E,
+        play_stochastically: bool,
+    ) -> MCTSOutput {
         for _ in 0..mcts::NUM_SEARCH {
             let mut path: Path = vec![Rc::clone(&self.root_node)];
             let mut last_node = Rc::clone(&path[0]);
@@ -106,31 +140,9 @@ impl TreeSearch {
                 } else {
                     // not ended so we create the child noddes
                     let legal_move_pool = last_node.game_state.get_legal_moves(None);
-                    // get net's output given the leaf's state
-                    let net_output = net.run(&last_node.game_state);
-
-                    // set all the illegal moves to constants::MASKING_VALUE, then softmax
-                    let mut masked_policy =
-                        Array3::from_elem(sizes::MOVE_SHAPE, constants::MASKING_VALUE);
-                    for mv in legal_move_pool.iter() {
-                        masked_policy[[mv.y, mv.x, mv.p]] =
-                            net_output
-                                .policy_head
-                                .get(&[mv.y as u64, mv.x as u64, mv.p as u64]);
-                    }
-                    let policy = raw_ops::reshape(
-                        &net.ctx,
-                        &raw_ops::softmax(&net.ctx, &Array::from_iter(masked_policy.into_iter()))
-                            .unwrap(),
-                        &[
-                            sizes::MOVE_SHAPE.0 as i32,
-                            sizes::MOVE_SHAPE.1 as i32,
-                            sizes::MOVE_SHAPE.2 as i32,
-                        ],
-                    )
-                    .unwrap()
-                    .resolve()
-                    .unwrap();
+                    // get evaluator's output given the leaf's state
+                    let evaluation = evaluator.evaluate(&last_node.game_state);
+                    let policy = TreeSearch::masked_softmax(&evaluation.policy, &legal_move_pool);
 
                     // generate new nodes
                     for mv in legal_move_pool {
@@ -139,12 +151,12 @@ impl TreeSearch {
 
                         last_node.children.push(Node::init_node(
                             new_game_state,
-                            policy.get(&[mv.y as u64, mv.x as u64, mv.p as u64]),
+                            policy[mv.get_move_arr()],
                             Some(mv),
                         ));
                     }
-                    // set the backup value to the net's output
-                    net_output.value_head
+                    // set the backup value to the evaluator's output
+                    evaluation.value
                 };
             }
             // backup
@@ -152,43 +164,60 @@ impl TreeSearch {
         }
 
         // ==Finding the best move==
-        let exp = if play_stochastically {
-            1.0 / mcts::EXPLORATION
-        } else {
-            10.0
-        };
+        let exp = pi_exponent(play_stochastically);
         // every "prob" of the moves
         let mut pi = Array3::zeros(sizes::MOVE_SHAPE);
         // calculate the weigths
         let mut weights: Vec<f32>;
+        let mut visits: Vec<(Move, usize)>;
+        let root_value;
         {
             let root_ref = self.root_node.borrow();
             let sum_n: usize = root_ref.iter_children().map(|child| child.borrow().n).sum();
 
             weights = Vec::with_capacity(root_ref.children.len());
+            visits = Vec::with_capacity(root_ref.children.len());
+            // the root's q is for the side that played before it
+            root_value = -root_ref.q;
 
             for child in root_ref.iter_children() {
                 let child_pi = ((child.borrow().n as f32) / (sum_n as f32)).powf(exp);
                 let child_move = child.borrow().m.expect("Node has no prior move");
                 weights.push(child_pi);
+                visits.push((child_move, child.borrow().n));
                 pi[child_move.get_move_arr()] = child_pi;
             }
         }
+        // the weights only sum to 1 when the exponent is 1
+        pi /= weights.iter().sum::<f32>();
 
         // get random move based on the weights, setting root node to the new node
         let dist = WeightedIndex::new(weights).expect("Root node is leaf node");
-        let mut rng = rand::thread_rng();
         self.root_node = Rc::clone(
             &Rc::clone(&self.root_node)
                 .borrow()
-                .get_child(dist.sample(&mut rng)),
+                .get_child(dist.sample(&mut self.rng)),
         );
 
         // return pi and the best move
         MCTSOutput {
             best_move: self.root_node.borrow().m.unwrap(),
             pi,
+            root_value,
+            visits,
+        }
+    }
+
+    /// Sets all the illegal moves to constants::MASKING_VALUE, then softmax
+    fn masked_softmax(policy: &Array3<f32>, legal_moves: &[Move]) -> Array3<f32> {
+        let mut masked_policy = Array3::from_elem(sizes::MOVE_SHAPE, constants::MASKING_VALUE);
+        for mv in legal_moves.iter() {
+            masked_policy[mv.get_move_arr()] = policy[mv.get_move_arr()];
         }
+        let max = masked_policy.fold(f32::NEG_INFINITY, |a, &b| a.max(b));
+        masked_policy.mapv_inplace(|x| (x - max).exp());
+        let sum = masked_policy.sum();
+        masked_policy / sum
     }
 
     /// Used in tree traversal
@@ -214,3 +243,80 @@ impl TreeSearch {
         argmax.map(|(i, _)| i)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::evaluator::Evaluation;
+
+    /// Likes the top left corner, has no opinion on the value
+    struct CornerEvaluator;
+    impl Evaluator for CornerEvaluator {
+        fn evaluate(&self, _game_state: &GameState) -> Evaluation {
+            let mut policy = Array3::zeros(sizes::MOVE_SHAPE);
+            policy[[0, 0, 0]] = 5.0;
+            Evaluation { value: 0.0, policy }
+        }
+    }
+
+    #[test]
+    fn masked_softmax_test() {
+        let mut game = GameState::init_game_state();
+        game.move_game(Move::new(0, 0), None);
+        let legal_moves = game.get_legal_moves(None);
+        let policy =
+            TreeSearch::masked_softmax(&CornerEvaluator.evaluate(&game).policy, &legal_moves);
+
+        assert!((policy.sum() - 1.0).abs() < 1e-4);
+        assert!(policy[[0, 0, 0]] < 1e-6, "illegal move wasn't masked");
+        let p = 1.0 / legal_moves.len() as f32;
+        assert!((policy[[5, 5, 0]] - p).abs() < 1e-6);
+    }
+
+    #[test]
+    fn search_without_net() {
+        let game = GameState::init_game_state();
+        let mut tree_search = TreeSearch::new(game.clone());
+        let output = tree_search.search(&CornerEvaluator, false);
+
+        assert_eq!(output.best_move, Move::new(0, 0));
+        let max_pi = output.pi.fold(0.0f32, |a, &b| a.max(b));
+        assert_eq!(output.pi[[0, 0, 0]], max_pi);
+        assert!((output.pi.sum() - 1.0).abs() < 1e-4);
+        assert_eq!(
+            output.visits.iter().map(|&(_, n)| n).sum::<usize>(),
+            mcts::NUM_SEARCH - 1
+        );
+    }
+
+    #[test]
+    fn won_in_one_test() {
+        // X has an open four on the first row, and the corner wins
+        let mut game = GameState::init_game_state();
+        for x in 1..5 {
+            game.move_game(Move::new(x, 0), None);
+            game.move_game(Move::new(2 * x, 12), None);
+        }
+        let mut tree_search = TreeSearch::new(game);
+        let output = tree_search.search(&CornerEvaluator, false);
+
+        assert_eq!(output.best_move, Move::new(0, 0));
+        assert!(output.root_value > 0.9, "root value {}", output.root_value);
+    }
+
+    #[test]
+    fn seeded_search_test() {
+        let play = |seed| {
+            let mut game = GameState::init_game_state();
+            let mut tree_search = TreeSearch::with_seed(game.clone(), seed);
+            (0..3)
+                .map(|_| {
+                    let mv = tree_search.search(&CornerEvaluator, true).best_move;
+                    game.move_game(mv, None);
+                    mv
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(play(7), play(7));
+    }
+}
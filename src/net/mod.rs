@@ -0,0 +1,146 @@
+//! Backends that run the CaroZero network.
+//! `tensorflow` loads the saved model (default feature `tensorflow`),
+//! `onnx` runs an ONNX export of it on the CPU (feature `onnx`),
+//! `native` runs exported weights in plain Rust.
+//! `training` trains the saved model with TensorFlow
+use std::fmt::{self, Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use ndarray::Array3;
+
+pub mod native;
+#[cfg(feature = "onnx")]
+pub mod onnx;
+#[cfg(feature = "tensorflow")]
+pub mod tensorflow;
+#[cfg(feature = "tensorflow")]
+pub mod training;
+
+/// Version of the model at `path`: when its files were last written, in seconds since the Unix epoch.
+/// It grows with every save or checkpoint, whichever script writes them, and stays the same across restarts
+pub fn model_version(path: &Path) -> u64 {
+    crate::hot_reload::last_modified(path)
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |since| since.as_secs())
+}
+
+pub struct NeuralNetOutput {
+    pub value_head: f32,
+    pub policy_head: Array3<f32>,
+}
+
+/// Why the network couldn't be loaded or run
+#[derive(Clone, Debug)]
+pub enum NetError {
+    /// The model file isn't there
+    NotFound(PathBuf),
+    /// The backend couldn't load the model
+    Load(String),
+    MissingSignature(String),
+    MissingInput(String),
+    MissingOutput(String),
+    /// The signature names an operation that isn't in the graph
+    MissingOperation(String),
+    /// An input or output doesn't have the shape the game uses
+    WrongShape {
+        name: String,
+        expected: String,
+        found: String,
+    },
+    /// The backend failed while evaluating
+    Run(String),
+    /// The trained weights couldn't be saved
+    Checkpoint(String),
+}
+
+impl Display for NetError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            NetError::NotFound(path) => write!(
+                f,
+                "{} not found, run 'python scripts/init_model.py' to generate it and try again",
+                path.display()
+            ),
+            NetError::Load(e) => write!(f, "can't load model: {}", e),
+            NetError::MissingSignature(name) => {
+                write!(f, "saved model has no signature '{}'", name)
+            }
+            NetError::MissingInput(name) => write!(f, "model has no input '{}'", name),
+            NetError::MissingOutput(name) => write!(f, "model has no output '{}'", name),
+            NetError::MissingOperation(name) => write!(f, "graph has no operation '{}'", name),
+            NetError::WrongShape {
+                name,
+                expected,
+                found,
+            } => write!(
+                f,
+                "'{}' has shape {}, but the game needs {}",
+                name, found, expected
+            ),
+            NetError::Run(e) => write!(f, "error during calculations: {}", e),
+            NetError::Checkpoint(e) => write!(f, "can't save checkpoint: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for NetError {}
+
+/// Helpers for the backends that evaluate batches as flat tensors
+#[cfg(any(feature = "tensorflow", feature = "onnx"))]
+mod batch {
+    use ndarray::Array3;
+
+    use crate::constants::sizes;
+    use crate::rules::types::GameState;
+
+    use super::{NetError, NeuralNetOutput};
+
+    /// Shape of a batched input or output, written like `[?, 13, 13, 9]`
+    pub(super) fn batched_shape(dims: &[usize]) -> String {
+        let dims: Vec<String> = dims.iter().map(|d| d.to_string()).collect();
+        format!("[?, {}]", dims.join(", "))
+    }
+
+    /// The network input for `game_states`, flattened in `[batch, y, x, plane]` order
+    pub(super) fn input_values(game_states: &[GameState]) -> Vec<f32> {
+        let mut values: Vec<f32> = Vec::with_capacity(
+            game_states.len()
+                * sizes::GAME_STATE_HEIGHT
+                * sizes::GAME_STATE_WIDTH
+                * sizes::GAME_STATE_PLANES,
+        );
+        for game_state in game_states {
+            values.extend(
+                game_state
+                    .get_contents_clone()
+                    .iter()
+                    .map(|&b| b as u8 as f32),
+            );
+        }
+        values
+    }
+
+    /// Splits the flat outputs of the network back into positions
+    pub(super) fn split_outputs(
+        values: &[f32],
+        policies: &[f32],
+    ) -> Result<Vec<NeuralNetOutput>, NetError> {
+        let policy_size = sizes::MOVE_SHAPE.0 * sizes::MOVE_SHAPE.1 * sizes::MOVE_SHAPE.2;
+        if policies.len() != values.len() * policy_size {
+            return Err(NetError::WrongShape {
+                name: "policy_head".into(),
+                expected: format!("{} values", values.len() * policy_size),
+                found: format!("{} values", policies.len()),
+            });
+        }
+        Ok(policies
+            .chunks(policy_size)
+            .zip(values.iter())
+            .map(|(policy, &value)| NeuralNetOutput {
+                value_head: value,
+                policy_head: Array3::from_shape_vec(sizes::MOVE_SHAPE, policy.to_vec()).unwrap(),
+            })
+            .collect())
+    }
+}
@@ -0,0 +1,388 @@
+//! Plain Rust forward pass of the network built by `scripts/init_model.py`,
+//! so the engine can run without any ML framework.
+//!
+//! The weights are a directory of `.npy` files written by `scripts/export_native.py`,
+//! batch normalizations already folded into a per-channel `scale` and `shift`:
+//! - `stem_conv`, `stem_bn_{scale,shift}`
+//! - `res{i}_conv{1,2}`, `res{i}_bn{1,2}_{scale,shift}` for every residual block `i`
+//! - `value_conv`, `value_bn_{scale,shift}`, `value_dense1`, `value_dense2`
+//! - `policy_conv`, `policy_bn_{scale,shift}`, `policy_head`
+//!
+//! Convolution kernels are `[height, width, in, out]` and dense kernels `[in, out]`, like in Keras
+use std::path::Path;
+
+use ndarray::{s, Array1, Array2, Array3, Array4, ArrayView3, Axis};
+use ndarray_npy::{read_npy, ReadableElement};
+
+use crate::constants::sizes;
+use crate::evaluator::{Evaluation, Evaluator, ModelId};
+use crate::rules::types::GameState;
+
+use super::{model_version, NetError, NeuralNetOutput};
+
+/// Convolution without bias, followed by a batch normalization
+struct ConvBn {
+    kernel: Array4<f32>,
+    scale: Array1<f32>,
+    shift: Array1<f32>,
+}
+
+impl ConvBn {
+    fn load(dir: &Path, conv: &str, bn: &str) -> Result<Self, NetError> {
+        let kernel: Array4<f32> = load_array(dir, conv)?;
+        let scale: Array1<f32> = load_array(dir, &format!("{bn}_scale"))?;
+        let shift: Array1<f32> = load_array(dir, &format!("{bn}_shift"))?;
+        let filters = kernel.shape()[3];
+        for (name, len) in [(bn, scale.len()), (bn, shift.len())] {
+            if len != filters {
+                return Err(NetError::WrongShape {
+                    name: name.into(),
+                    expected: format!("[{}]", filters),
+                    found: format!("[{}]", len),
+                });
+            }
+        }
+        Ok(ConvBn {
+            kernel,
+            scale,
+            shift,
+        })
+    }
+
+    fn in_channels(&self) -> usize {
+        self.kernel.shape()[2]
+    }
+
+    fn out_channels(&self) -> usize {
+        self.kernel.shape()[3]
+    }
+
+    /// Convolution and batch normalization, without the activation
+    fn forward(&self, x: ArrayView3<f32>) -> Array3<f32> {
+        let mut y = conv2d(x, &self.kernel);
+        for mut pixel in y.lanes_mut(Axis(2)) {
+            pixel.zip_mut_with(&self.scale, |v, &scale| *v *= scale);
+            pixel += &self.shift;
+        }
+        y
+    }
+}
+
+pub struct NativeNet {
+    id: ModelId,
+    stem: ConvBn,
+    res_blocks: Vec<(ConvBn, ConvBn)>,
+    value_conv: ConvBn,
+    value_dense1: Array2<f32>,
+    value_dense2: Array2<f32>,
+    policy_conv: ConvBn,
+    policy_head: Array4<f32>,
+}
+
+impl NativeNet {
+    /// Loads the weights in the directory `path`. The model is named after the directory
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, NetError> {
+        let path = path.as_ref();
+        let name = path
+            .file_name()
+            .map_or_else(|| path.to_string_lossy(), |name| name.to_string_lossy());
+        Self::load_named(path, &name)
+    }
+
+    /// Same as `load`, but the model is called `name` in logs and records
+    pub fn load_named<P: AsRef<Path>>(path: P, name: &str) -> Result<Self, NetError> {
+        let dir = path.as_ref();
+        if !dir.is_dir() {
+            return Err(NetError::NotFound(dir.to_path_buf()));
+        }
+        let stem = ConvBn::load(dir, "stem_conv", "stem_bn")?;
+        let mut res_blocks = Vec::new();
+        while dir
+            .join(format!("res{}_conv1.npy", res_blocks.len()))
+            .exists()
+        {
+            let i = res_blocks.len();
+            res_blocks.push((
+                ConvBn::load(dir, &format!("res{i}_conv1"), &format!("res{i}_bn1"))?,
+                ConvBn::load(dir, &format!("res{i}_conv2"), &format!("res{i}_bn2"))?,
+            ));
+        }
+        let net = NativeNet {
+            id: ModelId::new(name, model_version(dir)),
+            stem,
+            res_blocks,
+            value_conv: ConvBn::load(dir, "value_conv", "value_bn")?,
+            value_dense1: load_array(dir, "value_dense1")?,
+            value_dense2: load_array(dir, "value_dense2")?,
+            policy_conv: ConvBn::load(dir, "policy_conv", "policy_bn")?,
+            policy_head: load_array(dir, "policy_head")?,
+        };
+        net.check_shapes()?;
+        Ok(net)
+    }
+
+    /// Checks that the layers fit together and fit the game
+    fn check_shapes(&self) -> Result<(), NetError> {
+        let filters = self.stem.out_channels();
+        let board_size = sizes::GAME_STATE_HEIGHT * sizes::GAME_STATE_WIDTH;
+        // (layer, channels it has, channels it should have)
+        let mut checks = vec![(
+            "stem_conv".to_string(),
+            self.stem.in_channels(),
+            sizes::GAME_STATE_PLANES,
+        )];
+        for (i, (conv1, conv2)) in self.res_blocks.iter().enumerate() {
+            for (name, conv) in [
+                (format!("res{i}_conv1"), conv1),
+                (format!("res{i}_conv2"), conv2),
+            ] {
+                checks.push((name.clone(), conv.in_channels(), filters));
+                checks.push((name, conv.out_channels(), filters));
+            }
+        }
+        checks.extend([
+            ("value_conv".into(), self.value_conv.in_channels(), filters),
+            (
+                "value_dense1".into(),
+                self.value_dense1.shape()[0],
+                board_size * self.value_conv.out_channels(),
+            ),
+            (
+                "value_dense2".into(),
+                self.value_dense2.shape()[0],
+                self.value_dense1.shape()[1],
+            ),
+            ("value_dense2".into(), self.value_dense2.shape()[1], 1),
+            (
+                "policy_conv".into(),
+                self.policy_conv.in_channels(),
+                filters,
+            ),
+            (
+                "policy_head".into(),
+                self.policy_head.shape()[2],
+                self.policy_conv.out_channels(),
+            ),
+            (
+                "policy_head".into(),
+                self.policy_head.shape()[3],
+                sizes::MOVE_SHAPE.2,
+            ),
+        ]);
+        for (name, found, expected) in checks {
+            if found != expected {
+                return Err(NetError::WrongShape {
+                    name,
+                    expected: format!("{} channels", expected),
+                    found: format!("{} channels", found),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    pub fn id(&self) -> &ModelId {
+        &self.id
+    }
+
+    /// Evaluates the input planes of a single position, with shape `sizes::GAME_STATE_SHAPE`
+    fn forward(&self, x: ArrayView3<f32>) -> NeuralNetOutput {
+        let mut x = self.stem.forward(x);
+        x.mapv_inplace(gelu);
+        for (conv1, conv2) in self.res_blocks.iter() {
+            let mut y = conv1.forward(x.view());
+            y.mapv_inplace(gelu);
+            let mut y = conv2.forward(y.view());
+            y += &x;
+            y.mapv_inplace(gelu);
+            x = y;
+        }
+
+        let mut v = self.value_conv.forward(x.view());
+        v.mapv_inplace(gelu);
+        let v = Array1::from_iter(v.iter().cloned());
+        let v = v.dot(&self.value_dense1).mapv(gelu);
+        let value = v.dot(&self.value_dense2)[0].tanh();
+
+        let mut p = self.policy_conv.forward(x.view());
+        p.mapv_inplace(gelu);
+        let policy = conv2d(p.view(), &self.policy_head);
+
+        NeuralNetOutput {
+            value_head: value,
+            policy_head: policy,
+        }
+    }
+
+    pub fn run(&self, game_state: &GameState) -> NeuralNetOutput {
+        let x = game_state.get_contents_clone().mapv(|b| b as u8 as f32);
+        self.forward(x.view())
+    }
+
+    pub fn run_batch(&self, game_states: &[GameState]) -> Vec<NeuralNetOutput> {
+        game_states.iter().map(|gs| self.run(gs)).collect()
+    }
+}
+
+impl Evaluator for NativeNet {
+    fn evaluate(&self, game_state: &GameState) -> Evaluation {
+        let output = self.run(game_state);
+        Evaluation {
+            value: output.value_head,
+            policy: output.policy_head,
+        }
+    }
+    fn model_id(&self) -> ModelId {
+        self.id.clone()
+    }
+}
+
+fn load_array<A, D>(dir: &Path, name: &str) -> Result<ndarray::Array<A, D>, NetError>
+where
+    A: ReadableElement,
+    D: ndarray::Dimension,
+{
+    let path = dir.join(format!("{name}.npy"));
+    if !path.exists() {
+        return Err(NetError::NotFound(path));
+    }
+    read_npy(&path).map_err(|e| NetError::Load(format!("{}: {}", path.display(), e)))
+}
+
+/// Same as `tf.nn.gelu(x, approximate=True)`
+fn gelu(x: f32) -> f32 {
+    const SQRT_2_OVER_PI: f32 = 0.797_884_6;
+    0.5 * x * (1.0 + (SQRT_2_OVER_PI * (x + 0.044715 * x * x * x)).tanh())
+}
+
+/// 2D convolution of `[y, x, in]` planes with a `[height, width, in, out]` kernel,
+/// zero padded so the output is the same size (like `padding="same"`)
+fn conv2d(x: ArrayView3<f32>, kernel: &Array4<f32>) -> Array3<f32> {
+    let (height, width, _) = x.dim();
+    let (kernel_height, kernel_width, _, out_channels) = kernel.dim();
+    // TensorFlow puts the extra padding of even kernels after
+    let (pad_y, pad_x) = ((kernel_height - 1) / 2, (kernel_width - 1) / 2);
+    let mut y = Array3::zeros((height, width, out_channels));
+    for oy in 0..height {
+        for ox in 0..width {
+            let mut out = y.slice_mut(s![oy, ox, ..]);
+            for ky in 0..kernel_height {
+                let Some(iy) = (oy + ky).checked_sub(pad_y).filter(|&iy| iy < height) else {
+                    continue;
+                };
+                for kx in 0..kernel_width {
+                    let Some(ix) = (ox + kx).checked_sub(pad_x).filter(|&ix| ix < width) else {
+                        continue;
+                    };
+                    out += &x
+                        .slice(s![iy, ix, ..])
+                        .dot(&kernel.slice(s![ky, kx, .., ..]));
+                }
+            }
+        }
+    }
+    y
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rules::types::Move;
+    use crate::test_util::TempDir;
+    use ndarray::Array;
+    use ndarray_npy::write_npy;
+    use rand::Rng;
+
+    #[test]
+    fn conv2d_test() {
+        // a single stone spreads to the 3x3 square around it, cut at the edge of the board
+        let mut x = Array3::zeros((13, 13, 1));
+        x[[0, 4, 0]] = 1.0;
+        let kernel = Array4::ones((3, 3, 1, 2));
+        let y = conv2d(x.view(), &kernel);
+        assert_eq!(y.shape(), &[13, 13, 2]);
+        assert_eq!(y.sum(), 6.0 * 2.0);
+        assert_eq!(y[[1, 5, 1]], 1.0);
+        assert_eq!(y[[2, 4, 0]], 0.0);
+
+        // the kernel isn't flipped, like in TensorFlow
+        let mut kernel = Array4::zeros((3, 3, 1, 1));
+        kernel[[0, 0, 0, 0]] = 1.0;
+        let y = conv2d(x.view(), &kernel);
+        assert_eq!(y[[1, 5, 0]], 1.0);
+        assert_eq!(y.sum(), 1.0);
+    }
+
+    #[test]
+    fn loads_exported_weights() {
+        let dir = TempDir::new("native");
+        let mut rng = rand::thread_rng();
+        let mut write = |name: &str, shape: &[usize]| {
+            let array = Array::from_shape_fn(shape, |_| rng.gen_range(-0.5..0.5f32));
+            write_npy(dir.join(format!("{name}.npy")), &array).unwrap();
+        };
+        let planes = sizes::GAME_STATE_PLANES;
+        let board_size = sizes::GAME_STATE_HEIGHT * sizes::GAME_STATE_WIDTH;
+        write("stem_conv", &[3, 3, planes, 4]);
+        for bn in ["stem_bn", "res0_bn1", "res0_bn2"] {
+            write(&format!("{bn}_scale"), &[4]);
+            write(&format!("{bn}_shift"), &[4]);
+        }
+        write("res0_conv1", &[3, 3, 4, 4]);
+        write("res0_conv2", &[3, 3, 4, 4]);
+        write("value_conv", &[1, 1, 4, 1]);
+        write("value_bn_scale", &[1]);
+        write("value_bn_shift", &[1]);
+        write("value_dense1", &[board_size, 8]);
+        write("value_dense2", &[8, 1]);
+        write("policy_conv", &[3, 3, 4, 6]);
+        write("policy_bn_scale", &[6]);
+        write("policy_bn_shift", &[6]);
+        write("policy_head", &[1, 1, 6, 1]);
+
+        let net = NativeNet::load(&dir).unwrap();
+        assert_eq!(net.res_blocks.len(), 1);
+        let mut game = GameState::init_game_state();
+        game.move_game(Move::new(6, 6), None);
+        let outputs = net.run_batch(&[GameState::init_game_state(), game.clone()]);
+        let output = net.run(&game);
+        assert!(output.value_head.abs() < 1.0);
+        assert_eq!(output.policy_head.shape(), &[13, 13, 1]);
+        assert_eq!(output.policy_head, outputs[1].policy_head);
+
+        // a layer that doesn't fit is caught when loading
+        write("value_dense2", &[7, 1]);
+        assert!(matches!(
+            NativeNet::load(&dir),
+            Err(NetError::WrongShape { .. })
+        ));
+    }
+
+    /// Compares with the TensorFlow model the weights were exported from
+    #[cfg(feature = "tensorflow")]
+    #[test]
+    #[ignore = "needs a trained model exported by scripts/export_native.py"]
+    fn matches_tensorflow() {
+        use crate::constants;
+        use crate::net::tensorflow::NeuralNet;
+        use rand::rngs::StdRng;
+        use rand::SeedableRng;
+
+        let net = NeuralNet::load(constants::model::NET_PATH).unwrap();
+        let native = NativeNet::load(constants::model::NATIVE_PATH).unwrap();
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut game = GameState::init_game_state();
+        for _ in 0..16 {
+            let expected = net.run(&game).unwrap();
+            let output = native.run(&game);
+            assert!((output.value_head - expected.value_head).abs() < 1e-4);
+            assert!(output
+                .policy_head
+                .iter()
+                .zip(expected.policy_head.iter())
+                .all(|(a, b)| (a - b).abs() < 1e-3));
+            let moves = game.get_legal_moves(None);
+            game.move_game(moves[rng.gen_range(0..moves.len())], None);
+        }
+    }
+}
@@ -0,0 +1,197 @@
+//! Runs an ONNX export of the model (see `scripts/export_onnx.py`) on the CPU with tract,
+//! without needing libtensorflow
+use std::path::Path;
+
+use tract_onnx::prelude::*;
+
+use crate::constants::sizes;
+use crate::evaluator::{Evaluation, Evaluator, ModelId};
+use crate::rules::types::GameState;
+
+use super::batch::{batched_shape, input_values, split_outputs};
+use super::{model_version, NetError, NeuralNetOutput};
+
+type Plan = SimplePlan<TypedFact, Box<dyn TypedOp>, Graph<TypedFact, Box<dyn TypedOp>>>;
+
+pub struct OnnxNet {
+    id: ModelId,
+    plan: Plan,
+    value_head_index: usize,
+    policy_head_index: usize,
+}
+
+impl OnnxNet {
+    /// Loads the ONNX model at `path`, checking that its input and outputs fit the game.
+    /// The model is named after the file
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, NetError> {
+        let path = path.as_ref();
+        let name = path
+            .file_stem()
+            .map_or_else(|| path.to_string_lossy(), |name| name.to_string_lossy());
+        Self::load_named(path, &name)
+    }
+
+    /// Same as `load`, but the model is called `name` in logs and records
+    pub fn load_named<P: AsRef<Path>>(path: P, name: &str) -> Result<Self, NetError> {
+        let path = path.as_ref();
+        if !path.is_file() {
+            return Err(NetError::NotFound(path.to_path_buf()));
+        }
+        let load_error = |e: TractError| NetError::Load(e.to_string());
+
+        let mut model = tract_onnx::onnx()
+            .model_for_path(path)
+            .map_err(load_error)?;
+        if model.inputs.len() != 1 {
+            return Err(NetError::MissingInput("main_input".into()));
+        }
+        // any batch size
+        let batch = model.symbol_table.sym("N");
+        let input_shape: TVec<TDim> = tvec![
+            batch.into(),
+            sizes::GAME_STATE_HEIGHT.into(),
+            sizes::GAME_STATE_WIDTH.into(),
+            sizes::GAME_STATE_PLANES.into(),
+        ];
+        model
+            .set_input_fact(0, InferenceFact::dt_shape(f32::datum_type(), input_shape))
+            .map_err(load_error)?;
+        let model = model.into_typed().map_err(|e| NetError::WrongShape {
+            name: "main_input".into(),
+            expected: batched_shape(&[
+                sizes::GAME_STATE_HEIGHT,
+                sizes::GAME_STATE_WIDTH,
+                sizes::GAME_STATE_PLANES,
+            ]),
+            found: e.to_string(),
+        })?;
+
+        // tell the heads apart by their rank, then check the rest of their shape
+        let policy_shape = [
+            sizes::MOVE_SHAPE.0,
+            sizes::MOVE_SHAPE.1,
+            sizes::MOVE_SHAPE.2,
+        ];
+        let mut value_head_index = None;
+        let mut policy_head_index = None;
+        for (i, outlet) in model
+            .output_outlets()
+            .map_err(load_error)?
+            .iter()
+            .enumerate()
+        {
+            let shape = &model.outlet_fact(*outlet).map_err(load_error)?.shape;
+            let (name, expected, index): (&str, &[usize], _) = match shape.rank() {
+                2 => ("value_head", &[1], &mut value_head_index),
+                4 => ("policy_head", &policy_shape, &mut policy_head_index),
+                _ => continue,
+            };
+            let matches = shape
+                .iter()
+                .skip(1)
+                .zip(expected)
+                .all(|(dim, &want)| dim.to_i64().ok().is_none_or(|d| d == want as i64));
+            if !matches {
+                return Err(NetError::WrongShape {
+                    name: name.into(),
+                    expected: batched_shape(expected),
+                    found: format!("{:?}", shape),
+                });
+            }
+            *index = Some(i);
+        }
+        let value_head_index =
+            value_head_index.ok_or_else(|| NetError::MissingOutput("value_head".into()))?;
+        let policy_head_index =
+            policy_head_index.ok_or_else(|| NetError::MissingOutput("policy_head".into()))?;
+
+        let plan = model
+            .into_optimized()
+            .and_then(|model| model.into_runnable())
+            .map_err(load_error)?;
+        Ok(OnnxNet {
+            id: ModelId::new(name, model_version(path)),
+            plan,
+            value_head_index,
+            policy_head_index,
+        })
+    }
+
+    pub fn id(&self) -> &ModelId {
+        &self.id
+    }
+
+    pub fn run(&self, game_state: &GameState) -> Result<NeuralNetOutput, NetError> {
+        Ok(self.run_batch(std::slice::from_ref(game_state))?.remove(0))
+    }
+
+    /// Evaluates all of `game_states` with a single run of the model
+    pub fn run_batch(&self, game_states: &[GameState]) -> Result<Vec<NeuralNetOutput>, NetError> {
+        if game_states.is_empty() {
+            return Ok(Vec::new());
+        }
+        let run_error = |e: TractError| NetError::Run(e.to_string());
+        let x = tract_ndarray::Array4::from_shape_vec(
+            (
+                game_states.len(),
+                sizes::GAME_STATE_HEIGHT,
+                sizes::GAME_STATE_WIDTH,
+                sizes::GAME_STATE_PLANES,
+            ),
+            input_values(game_states),
+        )
+        .unwrap();
+        let outputs = self
+            .plan
+            .run(tvec!(Tensor::from(x).into()))
+            .map_err(run_error)?;
+        let value_head_output = outputs[self.value_head_index]
+            .as_slice::<f32>()
+            .map_err(run_error)?;
+        let policy_head_output = outputs[self.policy_head_index]
+            .as_slice::<f32>()
+            .map_err(run_error)?;
+        split_outputs(value_head_output, policy_head_output)
+    }
+}
+
+impl Evaluator for OnnxNet {
+    fn evaluate(&self, game_state: &GameState) -> Evaluation {
+        let output = self.run(game_state).unwrap_or_else(|e| panic!("{}", e));
+        Evaluation {
+            value: output.value_head,
+            policy: output.policy_head,
+        }
+    }
+    fn evaluate_batch(&self, game_states: &[GameState]) -> Vec<Evaluation> {
+        self.try_evaluate_batch(game_states)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+    fn try_evaluate_batch(&self, game_states: &[GameState]) -> Result<Vec<Evaluation>, NetError> {
+        Ok(self
+            .run_batch(game_states)?
+            .into_iter()
+            .map(|output| Evaluation {
+                value: output.value_head,
+                policy: output.policy_head,
+            })
+            .collect())
+    }
+    fn model_id(&self) -> ModelId {
+        self.id.clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn missing_model_test() {
+        match OnnxNet::load("there/is/no/model.onnx") {
+            Err(NetError::NotFound(path)) => assert!(path.ends_with("model.onnx")),
+            Err(e) => panic!("wrong error: {}", e),
+            Ok(_) => panic!("loaded a model that doesn't exist"),
+        }
+    }
+}
@@ -0,0 +1,326 @@
+//! Runs the saved model with TensorFlow
+use std::fs;
+use std::path::Path;
+
+use tensorflow::{
+    eager::{self, raw_ops, Context},
+    Graph, Operation, Output, SavedModelBundle, SessionOptions, SessionRunArgs, Status, Tensor,
+    TensorInfo, DEFAULT_SERVING_SIGNATURE_DEF_KEY,
+};
+
+use crate::constants::sizes;
+use crate::evaluator::{Evaluation, Evaluator, ModelId};
+use crate::rules::types::GameState;
+
+use super::batch::{batched_shape, input_values, split_outputs};
+use super::{model_version, NetError, NeuralNetOutput};
+
+impl From<Status> for NetError {
+    fn from(status: Status) -> Self {
+        NetError::Run(status.to_string())
+    }
+}
+
+/// Checks that `info` has the shape `[batch, expected...]`, an unknown dimension matches anything
+fn check_shape(name: &str, info: &TensorInfo, expected: &[usize]) -> Result<(), NetError> {
+    let shape = info.shape();
+    let matches = match shape.dims() {
+        None => true,
+        Some(rank) => {
+            rank == expected.len() + 1
+                && expected
+                    .iter()
+                    .enumerate()
+                    .all(|(i, &dim)| shape[i + 1].is_none_or(|d| d == dim as i64))
+        }
+    };
+    if matches {
+        Ok(())
+    } else {
+        Err(NetError::WrongShape {
+            name: name.to_string(),
+            expected: batched_shape(expected),
+            found: shape.to_string(),
+        })
+    }
+}
+
+/// Directory of the saved model where the trainer keeps its checkpoints
+pub(super) const CHECKPOINT_DIR: &str = "checkpoints";
+/// File in `CHECKPOINT_DIR` holding the prefix of the newest checkpoint, relative to the saved model
+pub(super) const LATEST_CHECKPOINT: &str = "latest";
+
+/// The tensors behind the `inputs` and `outputs` of the signature `key`
+pub(super) fn signature_tensors(
+    bundle: &SavedModelBundle,
+    graph: &Graph,
+    key: &str,
+    inputs: &[&str],
+    outputs: &[&str],
+) -> Result<(Vec<Output>, Vec<Output>), NetError> {
+    let signature = bundle
+        .meta_graph_def()
+        .get_signature(key)
+        .map_err(|_| NetError::MissingSignature(key.into()))?;
+    let tensor = |info: &TensorInfo| {
+        Ok(Output {
+            operation: graph
+                .operation_by_name_required(&info.name().name)
+                .map_err(|_| NetError::MissingOperation(info.name().name.clone()))?,
+            index: info.name().index,
+        })
+    };
+    let inputs = inputs
+        .iter()
+        .map(|&name| {
+            let info = signature
+                .get_input(name)
+                .map_err(|_| NetError::MissingInput(name.into()))?;
+            tensor(info)
+        })
+        .collect::<Result<_, NetError>>()?;
+    let outputs = outputs
+        .iter()
+        .map(|&name| {
+            let info = signature
+                .get_output(name)
+                .map_err(|_| NetError::MissingOutput(name.into()))?;
+            tensor(info)
+        })
+        .collect::<Result<_, NetError>>()?;
+    Ok((inputs, outputs))
+}
+
+/// Runs a checkpoint signature, which takes and returns the prefix of the checkpoint files
+pub(super) fn run_checkpoint_signature(
+    bundle: &SavedModelBundle,
+    prefix_input: &Output,
+    prefix_output: &Output,
+    prefix: &Path,
+) -> Result<(), NetError> {
+    let prefix =
+        Tensor::<String>::new(&[]).with_values(&[prefix.to_string_lossy().into_owned()])?;
+    let mut args = SessionRunArgs::new();
+    args.add_feed(&prefix_input.operation, prefix_input.index, &prefix);
+    args.request_fetch(&prefix_output.operation, prefix_output.index);
+    bundle.session.run(&mut args)?;
+    Ok(())
+}
+
+/// Prefix of the newest checkpoint of the saved model in `path`, relative to `path`
+pub(super) fn latest_checkpoint(path: &Path) -> Option<String> {
+    fs::read_to_string(path.join(CHECKPOINT_DIR).join(LATEST_CHECKPOINT))
+        .ok()
+        .map(|prefix| prefix.trim().to_string())
+        .filter(|prefix| !prefix.is_empty())
+}
+
+/// Loads the weights of the newest checkpoint in `path` into `bundle`, if the model has been trained from Rust.
+/// Returns the prefix of the checkpoint
+pub(super) fn restore_latest_checkpoint(
+    bundle: &SavedModelBundle,
+    graph: &Graph,
+    path: &Path,
+) -> Result<Option<String>, NetError> {
+    let Some(prefix) = latest_checkpoint(path) else {
+        return Ok(None);
+    };
+    let (inputs, outputs) = signature_tensors(
+        bundle,
+        graph,
+        "restore_checkpoint",
+        &["prefix"],
+        &["prefix"],
+    )?;
+    run_checkpoint_signature(bundle, &inputs[0], &outputs[0], &path.join(&prefix))?;
+    Ok(Some(prefix))
+}
+
+pub struct NeuralNet {
+    id: ModelId,
+    ctx: Context,
+    bundle: SavedModelBundle,
+    x_op: Operation,
+    value_head_op: Operation,
+    policy_head_op: Operation,
+}
+impl NeuralNet {
+    /// Loads the saved model in the directory `path`, checking that its signature fits the game,
+    /// with the weights of its newest checkpoint if there is one.
+    /// The model is named after the directory
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, NetError> {
+        let path = path.as_ref();
+        let name = path
+            .file_name()
+            .map_or_else(|| path.to_string_lossy(), |name| name.to_string_lossy());
+        Self::load_named(path, &name)
+    }
+
+    /// Same as `load`, but the model is called `name` in logs and records
+    pub fn load_named<P: AsRef<Path>>(path: P, name: &str) -> Result<Self, NetError> {
+        let path = path.as_ref();
+        let model_file = path.join("saved_model.pb");
+        if !model_file.exists() {
+            return Err(NetError::NotFound(model_file));
+        }
+
+        // Create an eager execution context
+        let opts = eager::ContextOptions::new();
+        let ctx = eager::Context::new(opts).map_err(|e| NetError::Load(e.to_string()))?;
+
+        // Load the model.
+        let mut graph = Graph::new();
+        let bundle = SavedModelBundle::load(&SessionOptions::new(), ["serve"], &mut graph, path)
+            .map_err(|e| NetError::Load(e.to_string()))?;
+
+        // get in/out operations
+        let signature = bundle
+            .meta_graph_def()
+            .get_signature(DEFAULT_SERVING_SIGNATURE_DEF_KEY)
+            .map_err(|_| NetError::MissingSignature(DEFAULT_SERVING_SIGNATURE_DEF_KEY.into()))?;
+
+        let x_info = signature
+            .get_input("main_input")
+            .map_err(|_| NetError::MissingInput("main_input".into()))?;
+        let policy_head_info = signature
+            .get_output("policy_head")
+            .map_err(|_| NetError::MissingOutput("policy_head".into()))?;
+        let value_head_info = signature
+            .get_output("value_head")
+            .map_err(|_| NetError::MissingOutput("value_head".into()))?;
+
+        check_shape(
+            "main_input",
+            x_info,
+            &[
+                sizes::GAME_STATE_HEIGHT,
+                sizes::GAME_STATE_WIDTH,
+                sizes::GAME_STATE_PLANES,
+            ],
+        )?;
+        check_shape(
+            "policy_head",
+            policy_head_info,
+            &[
+                sizes::MOVE_SHAPE.0,
+                sizes::MOVE_SHAPE.1,
+                sizes::MOVE_SHAPE.2,
+            ],
+        )?;
+        check_shape("value_head", value_head_info, &[1])?;
+
+        let operation = |info: &TensorInfo| {
+            graph
+                .operation_by_name_required(&info.name().name)
+                .map_err(|_| NetError::MissingOperation(info.name().name.clone()))
+        };
+        let x_op = operation(x_info)?;
+        let policy_head_op = operation(policy_head_info)?;
+        let value_head_op = operation(value_head_info)?;
+
+        restore_latest_checkpoint(&bundle, &graph, path)?;
+        Ok(NeuralNet {
+            id: ModelId::new(name, model_version(path)),
+            bundle,
+            ctx,
+            x_op,
+            value_head_op,
+            policy_head_op,
+        })
+    }
+
+    pub fn id(&self) -> &ModelId {
+        &self.id
+    }
+
+    pub fn run(&self, game_state: &GameState) -> Result<NeuralNetOutput, NetError> {
+        let bool_x = Tensor::from(game_state.get_contents_clone()).freeze();
+        let cast2float = raw_ops::Cast::new().DstT(tensorflow::DataType::Float);
+        let float_x = cast2float.call(&self.ctx, &bool_x)?;
+        let batched_x = raw_ops::expand_dims(&self.ctx, &float_x, &0)?;
+        let readonly_x = batched_x.resolve()?;
+        let x: Tensor<f32> = unsafe { readonly_x.into_tensor() };
+
+        // Run the graph.
+        let mut args = SessionRunArgs::new();
+        args.add_feed(&self.x_op, 0, &x);
+
+        let value_head_token = args.request_fetch(&self.value_head_op, 0);
+        let policy_head_token = args.request_fetch(&self.policy_head_op, 0);
+
+        self.bundle.session.run(&mut args)?;
+        let value_head_output: Tensor<f32> = args.fetch(value_head_token)?;
+        let policy_head_output: Tensor<f32> = args.fetch(policy_head_token)?;
+        Ok(split_outputs(&value_head_output, &policy_head_output)?.remove(0))
+    }
+}
+impl NeuralNet {
+    /// Evaluates all of `game_states` with a single run of the graph
+    pub fn run_batch(&self, game_states: &[GameState]) -> Result<Vec<NeuralNetOutput>, NetError> {
+        if game_states.is_empty() {
+            return Ok(Vec::new());
+        }
+        let values = input_values(game_states);
+        let x = Tensor::new(&[
+            game_states.len() as u64,
+            sizes::GAME_STATE_HEIGHT as u64,
+            sizes::GAME_STATE_WIDTH as u64,
+            sizes::GAME_STATE_PLANES as u64,
+        ])
+        .with_values(&values)?;
+
+        // Run the graph.
+        let mut args = SessionRunArgs::new();
+        args.add_feed(&self.x_op, 0, &x);
+
+        let value_head_token = args.request_fetch(&self.value_head_op, 0);
+        let policy_head_token = args.request_fetch(&self.policy_head_op, 0);
+
+        self.bundle.session.run(&mut args)?;
+        let value_head_output: Tensor<f32> = args.fetch(value_head_token)?;
+        let policy_head_output: Tensor<f32> = args.fetch(policy_head_token)?;
+
+        split_outputs(&value_head_output, &policy_head_output)
+    }
+}
+
+impl Evaluator for NeuralNet {
+    fn evaluate(&self, game_state: &GameState) -> Evaluation {
+        let output = self.run(game_state).unwrap_or_else(|e| panic!("{}", e));
+        Evaluation {
+            value: output.value_head,
+            policy: output.policy_head,
+        }
+    }
+    fn evaluate_batch(&self, game_states: &[GameState]) -> Vec<Evaluation> {
+        self.try_evaluate_batch(game_states)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+    fn try_evaluate_batch(&self, game_states: &[GameState]) -> Result<Vec<Evaluation>, NetError> {
+        Ok(self
+            .run_batch(game_states)?
+            .into_iter()
+            .map(|output| Evaluation {
+                value: output.value_head,
+                policy: output.policy_head,
+            })
+            .collect())
+    }
+    fn model_id(&self) -> ModelId {
+        self.id.clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn missing_model_test() {
+        match NeuralNet::load("there/is/no/model") {
+            Err(NetError::NotFound(path)) => assert!(path.ends_with("saved_model.pb")),
+            Err(e) => panic!("wrong error: {}", e),
+            Ok(_) => panic!("loaded a model that doesn't exist"),
+        }
+    }
+}
@@ -0,0 +1,341 @@
+//! Trains the saved model from Rust, through the `train_step`, `save_checkpoint` and `restore_checkpoint`
+//! signatures exported by `scripts/load_and_save_model.py`.
+//! The loss is the one `scripts/init_model.py` compiles: value MSE plus masked policy cross-entropy
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use ndarray::Axis;
+use rand::seq::SliceRandom;
+use tensorflow::{Graph, Output, SavedModelBundle, SessionOptions, SessionRunArgs, Tensor};
+
+use crate::constants::{model::training, sizes};
+use crate::rules::symmetry::{transform_planes, Symmetry};
+use crate::types::ReplayData;
+
+use super::tensorflow::{
+    restore_latest_checkpoint, run_checkpoint_signature, signature_tensors, CHECKPOINT_DIR,
+    LATEST_CHECKPOINT,
+};
+use super::NetError;
+
+pub struct TrainingConfig {
+    pub batch_size: usize,
+    pub epochs: usize,
+    /// Trains on the 8 symmetries of every position, like `scripts/model_trainer.py`
+    pub augment: bool,
+    /// Weight of the root value in the value target, see `ReplayData::value_target`
+    pub q_weight: f32,
+}
+
+impl Default for TrainingConfig {
+    fn default() -> Self {
+        Self {
+            batch_size: training::MINI_BATCH,
+            epochs: training::NUM_EPOCH,
+            augment: true,
+            q_weight: training::VALUE_TARGET_Q_WEIGHT,
+        }
+    }
+}
+
+/// Losses of one training step, averaged over its minibatch
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct LossRecord {
+    pub step: u64,
+    pub epoch: usize,
+    /// Weighted sum of the head losses and the regularization
+    pub loss: f32,
+    pub value_loss: f32,
+    pub policy_loss: f32,
+}
+
+/// A minibatch, flattened in the layout of the `train_step` inputs
+pub struct Batch {
+    len: usize,
+    game_states: Vec<f32>,
+    value_targets: Vec<f32>,
+    pis: Vec<f32>,
+}
+
+impl Batch {
+    /// Gathers the positions `samples` of `data`, each seen through its symmetry,
+    /// with value targets weighing the root value by `q_weight`
+    pub fn new(data: &ReplayData, samples: &[(usize, Symmetry)], q_weight: f32) -> Self {
+        let mut batch = Batch {
+            len: samples.len(),
+            game_states: Vec::with_capacity(
+                samples.len()
+                    * sizes::GAME_STATE_HEIGHT
+                    * sizes::GAME_STATE_WIDTH
+                    * sizes::GAME_STATE_PLANES,
+            ),
+            value_targets: Vec::with_capacity(samples.len()),
+            pis: Vec::with_capacity(
+                samples.len() * sizes::MOVE_HEIGHT * sizes::MOVE_WIDTH * sizes::MOVE_PLANES,
+            ),
+        };
+        for &(i, symmetry) in samples {
+            let game_state = data.game_states.index_axis(Axis(0), i).to_owned();
+            let pi = data.pis.index_axis(Axis(0), i).to_owned();
+            batch.game_states.extend(
+                transform_planes(&game_state, symmetry)
+                    .iter()
+                    .map(|&b| b as u8 as f32),
+            );
+            batch.pis.extend(transform_planes(&pi, symmetry).iter());
+            batch.value_targets.push(data.value_target(i, q_weight));
+        }
+        batch
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+pub struct Trainer {
+    path: PathBuf,
+    bundle: SavedModelBundle,
+    step: u64,
+    // main_input, value_target, policy_target
+    train_inputs: Vec<Output>,
+    // loss, value_loss, policy_loss
+    train_outputs: Vec<Output>,
+    save_input: Output,
+    save_output: Output,
+}
+
+impl Trainer {
+    /// Loads the saved model in the directory `path` for training,
+    /// resuming from its newest checkpoint if there is one
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, NetError> {
+        let path = path.as_ref();
+        let model_file = path.join("saved_model.pb");
+        if !model_file.exists() {
+            return Err(NetError::NotFound(model_file));
+        }
+
+        let mut graph = Graph::new();
+        let bundle = SavedModelBundle::load(&SessionOptions::new(), ["serve"], &mut graph, path)
+            .map_err(|e| NetError::Load(e.to_string()))?;
+        let (train_inputs, train_outputs) = signature_tensors(
+            &bundle,
+            &graph,
+            "train_step",
+            &["main_input", "value_target", "policy_target"],
+            &["loss", "value_loss", "policy_loss"],
+        )?;
+        let (mut save_inputs, mut save_outputs) =
+            signature_tensors(&bundle, &graph, "save_checkpoint", &["prefix"], &["prefix"])?;
+
+        // checkpoints are called after the number of steps trained
+        let step = restore_latest_checkpoint(&bundle, &graph, path)?
+            .and_then(|prefix| prefix.rsplit('-').next()?.parse().ok())
+            .unwrap_or(0);
+        Ok(Trainer {
+            path: path.to_path_buf(),
+            bundle,
+            step,
+            train_inputs,
+            train_outputs,
+            save_input: save_inputs.remove(0),
+            save_output: save_outputs.remove(0),
+        })
+    }
+
+    /// Number of steps trained so far, over all the checkpoints
+    pub fn step(&self) -> u64 {
+        self.step
+    }
+
+    /// Runs one optimizer step on `batch`
+    pub fn train_batch(&mut self, batch: &Batch, epoch: usize) -> Result<LossRecord, NetError> {
+        let len = batch.len as u64;
+        let game_states = Tensor::new(&[
+            len,
+            sizes::GAME_STATE_HEIGHT as u64,
+            sizes::GAME_STATE_WIDTH as u64,
+            sizes::GAME_STATE_PLANES as u64,
+        ])
+        .with_values(&batch.game_states)?;
+        let value_targets = Tensor::new(&[len, 1]).with_values(&batch.value_targets)?;
+        let pis = Tensor::new(&[
+            len,
+            sizes::MOVE_HEIGHT as u64,
+            sizes::MOVE_WIDTH as u64,
+            sizes::MOVE_PLANES as u64,
+        ])
+        .with_values(&batch.pis)?;
+
+        let mut args = SessionRunArgs::new();
+        for (input, tensor) in self
+            .train_inputs
+            .iter()
+            .zip([&game_states, &value_targets, &pis])
+        {
+            args.add_feed(&input.operation, input.index, tensor);
+        }
+        let tokens: Vec<_> = self
+            .train_outputs
+            .iter()
+            .map(|output| args.request_fetch(&output.operation, output.index))
+            .collect();
+        self.bundle.session.run(&mut args)?;
+        let mut losses = [0.0; 3];
+        for (loss, token) in losses.iter_mut().zip(tokens) {
+            let tensor: Tensor<f32> = args.fetch(token)?;
+            *loss = tensor[0];
+        }
+
+        self.step += 1;
+        Ok(LossRecord {
+            step: self.step,
+            epoch,
+            loss: losses[0],
+            value_loss: losses[1],
+            policy_loss: losses[2],
+        })
+    }
+
+    /// Trains on `data` for `config.epochs` epochs, shuffling the (augmented) positions every epoch.
+    /// `on_step` is called after every step, for progress reports
+    pub fn train<F>(
+        &mut self,
+        data: &ReplayData,
+        config: &TrainingConfig,
+        mut on_step: F,
+    ) -> Result<Vec<LossRecord>, NetError>
+    where
+        F: FnMut(&LossRecord),
+    {
+        let symmetries: &[Symmetry] = if config.augment {
+            &Symmetry::ALL
+        } else {
+            &[Symmetry::Identity]
+        };
+        let mut samples: Vec<(usize, Symmetry)> = (0..data.len())
+            .flat_map(|i| symmetries.iter().map(move |&symmetry| (i, symmetry)))
+            .collect();
+        let mut rng = rand::thread_rng();
+        let mut history = Vec::new();
+        for epoch in 0..config.epochs {
+            samples.shuffle(&mut rng);
+            for chunk in samples.chunks(config.batch_size) {
+                let record = self.train_batch(&Batch::new(data, chunk, config.q_weight), epoch)?;
+                on_step(&record);
+                history.push(record);
+            }
+        }
+        Ok(history)
+    }
+
+    /// Saves the weights to a new checkpoint and makes it the newest one,
+    /// so the next `NeuralNet::load` of the model uses them. Returns the prefix of the checkpoint
+    pub fn checkpoint(&self) -> Result<PathBuf, NetError> {
+        let checkpoint_error = |e: io::Error| NetError::Checkpoint(e.to_string());
+        let dir = self.path.join(CHECKPOINT_DIR);
+        fs::create_dir_all(&dir).map_err(checkpoint_error)?;
+        let prefix: PathBuf = [CHECKPOINT_DIR, &format!("ckpt-{}", self.step)]
+            .iter()
+            .collect();
+        run_checkpoint_signature(
+            &self.bundle,
+            &self.save_input,
+            &self.save_output,
+            &self.path.join(&prefix),
+        )?;
+
+        // swap the pointer in one go, so readers never see it half written
+        let latest = dir.join(LATEST_CHECKPOINT);
+        let temp = dir.join(format!("{LATEST_CHECKPOINT}.tmp"));
+        fs::write(&temp, prefix.to_string_lossy().as_bytes()).map_err(checkpoint_error)?;
+        fs::rename(&temp, &latest).map_err(checkpoint_error)?;
+        Ok(self.path.join(prefix))
+    }
+}
+
+/// Appends `history` to the CSV file at `path`, writing the header if the file is new
+pub fn append_loss_history<P: AsRef<Path>>(path: P, history: &[LossRecord]) -> io::Result<()> {
+    let path = path.as_ref();
+    let is_new = !path.exists();
+    let mut file = OpenOptions::new().append(true).create(true).open(path)?;
+    if is_new {
+        writeln!(file, "step,epoch,loss,value_loss,policy_loss")?;
+    }
+    for record in history {
+        writeln!(
+            file,
+            "{},{},{},{},{}",
+            record.step, record.epoch, record.loss, record.value_loss, record.policy_loss
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::TempDir;
+    use ndarray::{Array1, Array4};
+
+    #[test]
+    fn batch_test() {
+        let mut game_states = Array4::from_elem((2, 13, 13, 9), false);
+        game_states[[1, 0, 2, 0]] = true;
+        let mut pis = Array4::zeros((2, 13, 13, 1));
+        pis[[1, 0, 2, 0]] = 1.0;
+        let data = ReplayData {
+            game_states,
+            pis,
+            outcomes: Array1::from(vec![0.0, -1.0]),
+            root_values: Array1::from(vec![0.0, -0.5]),
+        };
+
+        let batch = Batch::new(&data, &[(1, Symmetry::Identity), (1, Symmetry::Rot90)], 0.5);
+        assert_eq!(batch.len(), 2);
+        assert_eq!(batch.value_targets, vec![-0.75, -0.75]);
+        let pis = Array4::from_shape_vec((2, 13, 13, 1), batch.pis).unwrap();
+        let game_states = Array4::from_shape_vec((2, 13, 13, 9), batch.game_states).unwrap();
+        // the stone and its move are moved together
+        let (x, y) = Symmetry::Rot90.apply(2, 0);
+        for (i, (x, y)) in [(2, 0), (x, y)].into_iter().enumerate() {
+            assert_eq!(pis[[i, y, x, 0]], 1.0);
+            assert_eq!(game_states[[i, y, x, 0]], 1.0);
+            assert_eq!(pis.index_axis(Axis(0), i).sum(), 1.0);
+            assert_eq!(game_states.index_axis(Axis(0), i).sum(), 1.0);
+        }
+    }
+
+    #[test]
+    fn loss_history_test() {
+        let dir = TempDir::new("loss_history");
+        let path = dir.join("loss_history.csv");
+        let record = LossRecord {
+            step: 1,
+            epoch: 0,
+            loss: 0.5,
+            value_loss: 0.25,
+            policy_loss: 0.75,
+        };
+        append_loss_history(&path, &[record]).unwrap();
+        append_loss_history(&path, &[LossRecord { step: 2, ..record }]).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "step,epoch,loss,value_loss,policy_loss\n1,0,0.5,0.25,0.75\n2,0,0.5,0.25,0.75\n"
+        );
+    }
+
+    #[test]
+    fn missing_model_test() {
+        match Trainer::load("there/is/no/model") {
+            Err(NetError::NotFound(path)) => assert!(path.ends_with("saved_model.pb")),
+            Err(e) => panic!("wrong error: {}", e),
+            Ok(_) => panic!("loaded a model that doesn't exist"),
+        }
+    }
+}
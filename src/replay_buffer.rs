@@ -0,0 +1,332 @@
+//! The window of recent games that training samples its positions from.
+//! The window is counted in games, or in generations of the model that played them, and grows with
+//! the number of them in the data: the first games are dropped quickly, while the net still changes
+//! a lot between steps, and later ones are kept for longer.
+//! Positions are sampled uniformly over the window, or favouring the most recent games
+use std::path::{Path, PathBuf};
+
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::Rng;
+
+use crate::evaluator::ModelId;
+use crate::shards::{self, ShardError, ShardInfo};
+use crate::types::ReplayData;
+
+/// What the size of the window is counted in
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum WindowUnit {
+    Games,
+    /// The games played by the same model, identified by its name and version
+    /// (see `net::model_version`), so the count carries over between runs
+    Generations,
+}
+
+/// The window holds the last `min + growth * n` units, at most `max`, where `n` is the number of units in the data
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Window {
+    pub unit: WindowUnit,
+    pub min: usize,
+    pub max: usize,
+    pub growth: f32,
+}
+
+impl Window {
+    /// Number of units in the window, when there are `played` in the data
+    pub fn size(&self, played: usize) -> usize {
+        let size = self.min + (self.growth * played as f32) as usize;
+        size.min(self.max).min(played)
+    }
+}
+
+/// How the positions of the window are picked
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Sampling {
+    /// Every position of the window is as likely
+    Uniform,
+    /// The positions of a game are half as likely as those of a game `half_life` games newer
+    Recency { half_life: f32 },
+}
+
+/// Where the positions of a game are
+#[derive(Clone, PartialEq, Eq, Debug)]
+struct GameEntry {
+    shard: usize,
+    /// Index of the first position of the game in its shard
+    start: usize,
+    len: usize,
+    generation: usize,
+}
+
+/// The games of a data directory, oldest first
+pub struct ReplayBuffer {
+    dir: PathBuf,
+    shards: Vec<ShardInfo>,
+    games: Vec<GameEntry>,
+    num_generations: usize,
+    window: Window,
+    sampling: Sampling,
+}
+
+impl ReplayBuffer {
+    /// Finds the games of the data directory `dir` from the index and the game records, without reading the positions.
+    /// A shard without records (converted from the old format) counts as one game.
+    /// Generations are numbered in the order their model first played
+    pub fn open<P: AsRef<Path>>(
+        dir: P,
+        window: Window,
+        sampling: Sampling,
+    ) -> Result<Self, ShardError> {
+        let dir = dir.as_ref();
+        let shards = shards::read_index(dir)?;
+        let mut games = Vec::new();
+        let mut models: Vec<Option<ModelId>> = Vec::new();
+        for (i, shard) in shards.iter().enumerate() {
+            let records = shards::read_records(dir, shard)?;
+            let mut lens: Vec<(usize, Option<ModelId>)> = records
+                .into_iter()
+                .map(|record| (record.turns.len(), Some(record.model)))
+                .collect();
+            if lens.is_empty() {
+                lens.push((shard.len, None));
+            }
+            if lens.iter().map(|(len, _)| len).sum::<usize>() != shard.len {
+                return Err(ShardError::Corrupt(format!(
+                    "the games recorded for {} don't add up to its {} positions",
+                    shard.file, shard.len
+                )));
+            }
+            let mut start = 0;
+            for (len, game_model) in lens {
+                // games of the old model can still finish after the new one started
+                let generation = match models.iter().position(|model| *model == game_model) {
+                    Some(generation) => generation,
+                    None => {
+                        models.push(game_model);
+                        models.len() - 1
+                    }
+                };
+                games.push(GameEntry {
+                    shard: i,
+                    start,
+                    len,
+                    generation,
+                });
+                start += len;
+            }
+        }
+        Ok(ReplayBuffer {
+            dir: dir.to_path_buf(),
+            shards,
+            games,
+            num_generations: models.len(),
+            window,
+            sampling,
+        })
+    }
+
+    pub fn num_games(&self) -> usize {
+        self.games.len()
+    }
+
+    pub fn num_generations(&self) -> usize {
+        self.num_generations
+    }
+
+    /// The games in the window, oldest first.
+    /// Counted in generations, the window holds the games played by its generations wherever they finished
+    fn window_games(&self) -> Vec<&GameEntry> {
+        match self.window.unit {
+            WindowUnit::Games => {
+                let start = self.num_games() - self.window.size(self.num_games());
+                self.games[start..].iter().collect()
+            }
+            WindowUnit::Generations => {
+                let played = self.num_generations();
+                let first = played - self.window.size(played);
+                self.games
+                    .iter()
+                    .filter(|game| game.generation >= first)
+                    .collect()
+            }
+        }
+    }
+
+    /// Number of games in the window
+    pub fn window_games_len(&self) -> usize {
+        self.window_games().len()
+    }
+
+    /// Number of positions in the window
+    pub fn window_len(&self) -> usize {
+        self.window_games().iter().map(|game| game.len).sum()
+    }
+
+    /// Samples `num_positions` positions of the window, with replacement,
+    /// reading only the shards they are in
+    pub fn sample<R: Rng>(
+        &self,
+        num_positions: usize,
+        rng: &mut R,
+    ) -> Result<ReplayData, ShardError> {
+        let games = self.window_games();
+        if num_positions == 0 || games.iter().all(|game| game.len == 0) {
+            return ReplayData::concatenate(Vec::new());
+        }
+        let newest = games.len() - 1;
+        // a game is picked as often as it has positions, then one of its positions
+        let weights = games.iter().enumerate().map(|(i, game)| {
+            let recency = match self.sampling {
+                Sampling::Uniform => 1.0,
+                Sampling::Recency { half_life } => {
+                    0.5f64.powf((newest - i) as f64 / half_life as f64)
+                }
+            };
+            recency * game.len as f64
+        });
+        let games_dist = WeightedIndex::new(weights)
+            .map_err(|e| ShardError::Corrupt(format!("can't weigh the games: {}", e)))?;
+        let mut picks: Vec<(usize, usize)> = (0..num_positions)
+            .map(|_| {
+                let game = &games[games_dist.sample(rng)];
+                (game.shard, game.start + rng.gen_range(0..game.len))
+            })
+            .collect();
+        picks.sort_unstable();
+
+        let mut parts = Vec::new();
+        for group in picks.chunk_by(|a, b| a.0 == b.0) {
+            let shard = shards::read_shard(&self.dir, &self.shards[group[0].0])?;
+            let indices: Vec<usize> = group.iter().map(|&(_, i)| i).collect();
+            parts.push(shard.select(&indices));
+        }
+        ReplayData::concatenate(parts)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::game_record::GameRecord;
+    use crate::net::model_version;
+    use crate::rules::types::GameResult;
+    use crate::test_util::{first_row_game, TempDir};
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use std::fs;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    /// A game of `len` moves on the first row, where every root value is the version of the model
+    fn record(version: u64, len: usize) -> GameRecord {
+        let mut record = first_row_game(&(0..len).collect::<Vec<_>>(), GameResult::Draws);
+        record.model = ModelId::new("test", version);
+        for turn in &mut record.turns {
+            turn.root_value = version as f32;
+        }
+        record
+    }
+
+    fn window(unit: WindowUnit, min: usize, max: usize, growth: f32) -> Window {
+        Window {
+            unit,
+            min,
+            max,
+            growth,
+        }
+    }
+
+    #[test]
+    fn window_test() {
+        let window = window(WindowUnit::Games, 10, 40, 0.5);
+        assert_eq!(window.size(4), 4);
+        assert_eq!(window.size(20), 20);
+        assert_eq!(window.size(40), 30);
+        assert_eq!(window.size(100), 40);
+    }
+
+    #[test]
+    fn replay_buffer_test() {
+        let dir = TempDir::new("replay_buffer");
+        shards::write_shard(&dir, &[record(0, 2), record(0, 3)]).unwrap();
+        shards::write_shard(&dir, &[record(1, 4)]).unwrap();
+        shards::write_shard(&dir, &[record(1, 1), record(2, 2)]).unwrap();
+        let mut rng = StdRng::seed_from_u64(1);
+
+        let buffer = ReplayBuffer::open(
+            &dir,
+            window(WindowUnit::Games, 2, 2, 0.0),
+            Sampling::Uniform,
+        )
+        .unwrap();
+        assert_eq!(buffer.num_games(), 5);
+        assert_eq!(buffer.num_generations(), 3);
+        assert_eq!(buffer.window_len(), 3);
+        let sample = buffer.sample(50, &mut rng).unwrap();
+        assert_eq!(sample.len(), 50);
+        assert!(sample.root_values.iter().all(|&v| v == 1.0 || v == 2.0));
+        // every position of the window gets picked
+        assert!(sample.root_values.iter().any(|&v| v == 1.0));
+        assert_eq!(sample.pis.sum(), 50.0);
+
+        // the window grows with the generations played, up to its max
+        let buffer = ReplayBuffer::open(
+            &dir,
+            window(WindowUnit::Generations, 1, 2, 0.5),
+            Sampling::Uniform,
+        )
+        .unwrap();
+        assert_eq!(buffer.window_games_len(), 3);
+        assert_eq!(buffer.window_len(), 7);
+        let sample = buffer.sample(100, &mut rng).unwrap();
+        assert!(sample.root_values.iter().all(|&v| v >= 1.0));
+
+        // recent games are sampled much more often
+        let buffer = ReplayBuffer::open(
+            &dir,
+            window(WindowUnit::Games, 5, 5, 0.0),
+            Sampling::Recency { half_life: 0.2 },
+        )
+        .unwrap();
+        assert_eq!(buffer.window_len(), 12);
+        let sample = buffer.sample(100, &mut rng).unwrap();
+        assert!(sample.root_values.iter().filter(|&&v| v == 2.0).count() > 90);
+    }
+
+    #[test]
+    fn generations_across_runs() {
+        let dir = TempDir::new("generations");
+        let data = dir.join("training_data");
+        fs::create_dir_all(&data).unwrap();
+        let model = dir.join("saved_model.pb");
+        let save_model = |secs| {
+            let file = fs::File::create(&model).unwrap();
+            file.set_modified(UNIX_EPOCH + Duration::from_secs(secs))
+                .unwrap();
+        };
+
+        // a run plays the first model, then a new process loads it again
+        save_model(1000);
+        let first = model_version(&model);
+        shards::write_shard(&data, &[record(first, 2)]).unwrap();
+        shards::write_shard(&data, &[record(model_version(&model), 3)]).unwrap();
+        // the model is trained, and a game of the old one finishes after the new one started
+        save_model(2000);
+        let second = model_version(&model);
+        shards::write_shard(
+            &data,
+            &[record(second, 1), record(first, 1), record(second, 2)],
+        )
+        .unwrap();
+
+        let buffer = ReplayBuffer::open(
+            &data,
+            window(WindowUnit::Generations, 1, 1, 0.0),
+            Sampling::Uniform,
+        )
+        .unwrap();
+        assert_eq!(buffer.num_games(), 5);
+        assert_eq!(buffer.num_generations(), 2);
+        // the game of the old model that finished late isn't in the window
+        assert_eq!(buffer.window_games_len(), 2);
+        assert_eq!(buffer.window_len(), 3);
+    }
+}
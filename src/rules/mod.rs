@@ -4,6 +4,9 @@ use std::cmp::min;
 
 use ndarray::{ArrayBase, Data, Dim, RawData};
 
+pub mod patterns;
+pub mod symmetry;
+pub mod tactics;
 pub mod types;
 
 /// Makes sure that the constants are valid
@@ -120,6 +123,12 @@ mod test {
             board.get_legal_moves(None).len(),
             sizes::BOARD_HEIGHT * sizes::BOARD_WIDTH - 4
         );
+        let onehot = board.legal_moves_onehot(None);
+        assert_eq!(onehot.dim(), sizes::MOVE_SHAPE);
+        assert_eq!(
+            onehot.iter().filter(|&&legal| legal).count(),
+            sizes::BOARD_HEIGHT * sizes::BOARD_WIDTH - 4
+        );
     }
 
     #[test]
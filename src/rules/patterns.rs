@@ -0,0 +1,413 @@
+//! Recognition of line patterns (fives, fours, threes) on a `Board`
+use std::collections::BTreeMap;
+
+use ndarray::{Data, RawData};
+
+use crate::constants::{self, sizes};
+
+use super::types::{Board, Move, Side};
+
+/// The 4 lines going through a square (the other 4 directions are their opposites)
+pub const LINES: [(i8, i8); 4] = [(1, 0), (0, 1), (1, 1), (1, -1)];
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub enum PatternKind {
+    /// `NUM_IN_A_ROW_FOR_WIN` or more in a row
+    Five,
+    /// Four that can be completed on two different squares, e.g. `-XXXX-`
+    OpenFour,
+    /// Four that can only be completed on one square, e.g. `OXXXX-` or `XX-XX`
+    Four,
+    /// Three in a row that can become an open four, e.g. `--XXX--`
+    OpenThree,
+    /// Three with a gap that can become an open four, e.g. `-X-XX-`
+    BrokenThree,
+    /// Three that can only become a simple four, e.g. `OXXX--`
+    ClosedThree,
+}
+
+#[derive(Clone, Debug)]
+pub struct Pattern {
+    pub kind: PatternKind,
+    pub side: Side,
+    /// The pieces making up the pattern
+    pub stones: Vec<Move>,
+    /// Empty squares that upgrade the pattern: squares completing a five for fours,
+    /// squares making a four for threes. Empty for fives
+    pub completions: Vec<Move>,
+}
+
+impl Pattern {
+    pub fn contains(&self, mv: Move) -> bool {
+        self.stones.contains(&mv)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Cell {
+    Own,
+    Opponent,
+    Empty,
+}
+
+/// The square `k` steps away from (x, y) in direction (dx, dy), if it's on the board
+pub(super) fn offset(x: usize, y: usize, (dx, dy): (i8, i8), k: i8) -> Option<(usize, usize)> {
+    let px = x as i32 + (dx * k) as i32;
+    let py = y as i32 + (dy * k) as i32;
+    if 0 <= px && px < sizes::BOARD_WIDTH as i32 && 0 <= py && py < sizes::BOARD_HEIGHT as i32 {
+        Some((px as usize, py as usize))
+    } else {
+        None
+    }
+}
+
+impl<T: Data + RawData<Elem = bool>> Board<T> {
+    pub fn is_empty_grid(&self, x: usize, y: usize) -> bool {
+        !self.get_grid(x, y, Side::X.plane_index()) && !self.get_grid(x, y, Side::O.plane_index())
+    }
+
+    /// Whether playing `mv` makes five in a row for `side`, according to the active rules
+    pub fn is_winning_move(&self, mv: Move, side: Side) -> bool {
+        self.is_empty_grid(mv.x, mv.y)
+            && LINES.iter().any(|&(dx, dy)| {
+                let (forward, forward_blocked) = self.run_length(side, mv, (dx, dy));
+                let (backward, backward_blocked) = self.run_length(side, mv, (-dx, -dy));
+                forward + backward + 1 >= sizes::NUM_IN_A_ROW_FOR_WIN
+                    && !(constants::BLOCKED_HEADS_RULE && forward_blocked && backward_blocked)
+            })
+    }
+
+    /// Every move that makes five in a row for `side`
+    pub fn winning_moves(&self, side: Side) -> Vec<Move> {
+        let mut res = Vec::new();
+        for y in 0..sizes::BOARD_HEIGHT {
+            for x in 0..sizes::BOARD_WIDTH {
+                let mv = Move::new(x, y);
+                if self.is_winning_move(mv, side) {
+                    res.push(mv);
+                }
+            }
+        }
+        res
+    }
+
+    /// Every line pattern of `side` on the board
+    pub fn find_patterns(&self, side: Side) -> Vec<Pattern> {
+        let mut res = Vec::new();
+        for dir in LINES {
+            for y in 0..sizes::BOARD_HEIGHT {
+                for x in 0..sizes::BOARD_WIDTH {
+                    // only start from the first square of each line
+                    if offset(x, y, dir, -1).is_none() {
+                        res.extend(line_patterns(side, &self.line_cells(side, x, y, dir)));
+                    }
+                }
+            }
+        }
+        res
+    }
+
+    /// Line patterns of `side` that include the piece on `mv`
+    pub fn find_patterns_through(&self, mv: Move, side: Side) -> Vec<Pattern> {
+        let mut res = Vec::new();
+        for dir in LINES {
+            let mut k = 0;
+            while offset(mv.x, mv.y, dir, k - 1).is_some() {
+                k -= 1;
+            }
+            let (x, y) = offset(mv.x, mv.y, dir, k).unwrap();
+            res.extend(
+                line_patterns(side, &self.line_cells(side, x, y, dir))
+                    .into_iter()
+                    .filter(|pattern| pattern.contains(mv)),
+            );
+        }
+        res
+    }
+
+    /// Counts the `side` pieces next to `mv` going in direction `dir`,
+    /// and whether that run is ended by an opponent piece
+    fn run_length(&self, side: Side, mv: Move, dir: (i8, i8)) -> (usize, bool) {
+        let mut k = 1;
+        while let Some((x, y)) = offset(mv.x, mv.y, dir, k) {
+            if !self.get_grid(x, y, side.plane_index()) {
+                return (
+                    k as usize - 1,
+                    self.get_grid(x, y, side.opponent().plane_index()),
+                );
+            }
+            k += 1;
+        }
+        (k as usize - 1, false)
+    }
+
+    /// The whole line starting at (x, y) going in direction `dir`, seen from `side`
+    fn line_cells(&self, side: Side, x: usize, y: usize, dir: (i8, i8)) -> Vec<(Move, Cell)> {
+        let mut res = Vec::new();
+        let mut k = 0;
+        while let Some((px, py)) = offset(x, y, dir, k) {
+            let cell = if self.get_grid(px, py, side.plane_index()) {
+                Cell::Own
+            } else if self.get_grid(px, py, side.opponent().plane_index()) {
+                Cell::Opponent
+            } else {
+                Cell::Empty
+            };
+            res.push((Move::new(px, py), cell));
+            k += 1;
+        }
+        res
+    }
+}
+
+/// Whether putting an own piece on the empty `cells[i]` makes a five along the line
+fn completes_five(cells: &[Cell], i: usize) -> bool {
+    if cells[i] != Cell::Empty {
+        return false;
+    }
+    let backward = cells[..i]
+        .iter()
+        .rev()
+        .take_while(|&&c| c == Cell::Own)
+        .count();
+    let forward = cells[i + 1..]
+        .iter()
+        .take_while(|&&c| c == Cell::Own)
+        .count();
+    let backward_blocked = i > backward && cells[i - backward - 1] == Cell::Opponent;
+    let forward_blocked = cells.get(i + forward + 1) == Some(&Cell::Opponent);
+    backward + forward + 1 >= sizes::NUM_IN_A_ROW_FOR_WIN
+        && !(constants::BLOCKED_HEADS_RULE && backward_blocked && forward_blocked)
+}
+
+/// Windows of `NUM_IN_A_ROW_FOR_WIN` cells without opponent pieces that contain all of `stones`
+fn open_windows<'a>(
+    cells: &'a [Cell],
+    stones: &'a [usize],
+) -> impl Iterator<Item = std::ops::Range<usize>> + 'a {
+    let n = sizes::NUM_IN_A_ROW_FOR_WIN;
+    (0..(cells.len() + 1).saturating_sub(n))
+        .map(move |start| start..start + n)
+        .filter(move |w| {
+            stones.iter().all(|s| w.contains(s))
+                && cells[w.clone()].iter().all(|&c| c != Cell::Opponent)
+        })
+}
+
+/// Squares completing a five with the four pieces at `stones`
+fn four_completions(cells: &[Cell], stones: &[usize]) -> Vec<usize> {
+    let mut res = Vec::new();
+    for w in open_windows(cells, stones) {
+        let own = w.clone().filter(|&i| cells[i] == Cell::Own).count();
+        if own != stones.len() {
+            continue;
+        }
+        for i in w {
+            if cells[i] == Cell::Empty && completes_five(cells, i) && !res.contains(&i) {
+                res.push(i);
+            }
+        }
+    }
+    res
+}
+
+fn line_patterns(side: Side, line: &[(Move, Cell)]) -> Vec<Pattern> {
+    let n = sizes::NUM_IN_A_ROW_FOR_WIN;
+    let mut cells: Vec<Cell> = line.iter().map(|&(_, c)| c).collect();
+    let to_moves =
+        |indices: &[usize]| -> Vec<Move> { indices.iter().map(|&i| line[i].0).collect() };
+    let mut res = Vec::new();
+
+    // fives: runs of at least n pieces
+    let mut fives: Vec<Vec<usize>> = Vec::new();
+    let mut i = 0;
+    while i < cells.len() {
+        let run = cells[i..].iter().take_while(|&&c| c == Cell::Own).count();
+        if run >= n {
+            let blocked = i > 0
+                && cells[i - 1] == Cell::Opponent
+                && cells.get(i + run) == Some(&Cell::Opponent);
+            if !(constants::BLOCKED_HEADS_RULE && blocked) {
+                fives.push((i..i + run).collect());
+            }
+        }
+        i += run.max(1);
+    }
+
+    // group the windows by the pieces they contain
+    let mut fours: BTreeMap<Vec<usize>, Vec<usize>> = BTreeMap::new();
+    let mut threes: BTreeMap<Vec<usize>, Vec<usize>> = BTreeMap::new();
+    for start in 0..(cells.len() + 1).saturating_sub(n) {
+        let w = start..start + n;
+        if cells[w.clone()].contains(&Cell::Opponent) {
+            continue;
+        }
+        let own: Vec<usize> = w.clone().filter(|&i| cells[i] == Cell::Own).collect();
+        if own.len() == n - 1 {
+            fours.entry(own).or_default();
+        } else if own.len() == n - 2 {
+            let empties = threes.entry(own).or_default();
+            for i in w.filter(|&i| cells[i] == Cell::Empty) {
+                if !empties.contains(&i) {
+                    empties.push(i);
+                }
+            }
+        }
+    }
+    let is_subset = |small: &[usize], bigs: &[&Vec<usize>]| {
+        bigs.iter().any(|big| small.iter().all(|s| big.contains(s)))
+    };
+
+    let mut bigger: Vec<&Vec<usize>> = fives.iter().collect();
+    for (stones, _) in fours.iter() {
+        if is_subset(stones, &bigger) {
+            continue;
+        }
+        let completions = four_completions(&cells, stones);
+        let kind = match completions.len() {
+            0 => continue,
+            1 => PatternKind::Four,
+            _ => PatternKind::OpenFour,
+        };
+        res.push(Pattern {
+            kind,
+            side,
+            stones: to_moves(stones),
+            completions: to_moves(&completions),
+        });
+    }
+    bigger.extend(fours.keys());
+
+    for (stones, empties) in threes.iter() {
+        if is_subset(stones, &bigger) {
+            continue;
+        }
+        let mut completions = Vec::new();
+        let mut open = false;
+        for &e in empties {
+            cells[e] = Cell::Own;
+            let mut four: Vec<usize> = stones.clone();
+            four.push(e);
+            four.sort_unstable();
+            let num_completions = four_completions(&cells, &four).len();
+            cells[e] = Cell::Empty;
+            if num_completions > 0 {
+                completions.push(e);
+            }
+            open |= num_completions >= 2;
+        }
+        if completions.is_empty() {
+            continue;
+        }
+        let kind = if !open {
+            PatternKind::ClosedThree
+        } else if stones[2] - stones[0] == 2 {
+            PatternKind::OpenThree
+        } else {
+            PatternKind::BrokenThree
+        };
+        res.push(Pattern {
+            kind,
+            side,
+            stones: to_moves(stones),
+            completions: to_moves(&completions),
+        });
+    }
+    for stones in fives.iter() {
+        res.push(Pattern {
+            kind: PatternKind::Five,
+            side,
+            stones: to_moves(stones),
+            completions: Vec::new(),
+        });
+    }
+    res
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn board_with(
+        x_moves: &[(usize, usize)],
+        o_moves: &[(usize, usize)],
+    ) -> Board<ndarray::OwnedRepr<bool>> {
+        let mut board = Board::init_board();
+        for &(x, y) in x_moves {
+            board.set_grid(x, y, Side::X.plane_index(), true);
+        }
+        for &(x, y) in o_moves {
+            board.set_grid(x, y, Side::O.plane_index(), true);
+        }
+        board
+    }
+
+    fn kinds(patterns: &[Pattern]) -> Vec<PatternKind> {
+        let mut res: Vec<PatternKind> = patterns.iter().map(|p| p.kind).collect();
+        res.sort();
+        res
+    }
+
+    #[test]
+    fn recognizes_fours() {
+        let board = board_with(&[(3, 5), (4, 5), (5, 5), (6, 5)], &[]);
+        let patterns = board.find_patterns(Side::X);
+        assert_eq!(kinds(&patterns), vec![PatternKind::OpenFour]);
+        assert_eq!(patterns[0].completions.len(), 2);
+        assert!(board.find_patterns(Side::O).is_empty());
+
+        let board = board_with(&[(3, 5), (4, 5), (5, 5), (6, 5)], &[(2, 5)]);
+        let patterns = board.find_patterns(Side::X);
+        assert_eq!(kinds(&patterns), vec![PatternKind::Four]);
+        assert_eq!(patterns[0].completions, vec![Move::new(7, 5)]);
+        assert_eq!(board.winning_moves(Side::X), vec![Move::new(7, 5)]);
+
+        let board = board_with(&[(2, 2), (3, 3), (5, 5), (6, 6)], &[]);
+        let patterns = board.find_patterns(Side::X);
+        assert_eq!(kinds(&patterns), vec![PatternKind::Four]);
+        assert_eq!(patterns[0].completions, vec![Move::new(4, 4)]);
+    }
+
+    #[test]
+    fn recognizes_threes() {
+        let board = board_with(&[(4, 6), (5, 6), (6, 6)], &[]);
+        assert_eq!(
+            kinds(&board.find_patterns(Side::X)),
+            vec![PatternKind::OpenThree]
+        );
+
+        let board = board_with(&[(6, 3), (6, 5), (6, 6)], &[]);
+        assert_eq!(
+            kinds(&board.find_patterns(Side::X)),
+            vec![PatternKind::BrokenThree]
+        );
+
+        let board = board_with(&[(4, 6), (5, 6), (6, 6)], &[(3, 6)]);
+        assert_eq!(
+            kinds(&board.find_patterns(Side::X)),
+            vec![PatternKind::ClosedThree]
+        );
+
+        // pieces against the edge can't make an open four
+        let board = board_with(&[(0, 0), (1, 0), (2, 0)], &[]);
+        assert_eq!(
+            kinds(&board.find_patterns(Side::X)),
+            vec![PatternKind::ClosedThree]
+        );
+    }
+
+    #[test]
+    fn recognizes_fives() {
+        let board = board_with(&[(0, 0), (1, 1), (2, 2), (3, 3), (4, 4)], &[(5, 5)]);
+        let patterns = board.find_patterns(Side::X);
+        assert_eq!(kinds(&patterns), vec![PatternKind::Five]);
+        assert_eq!(patterns[0].stones.len(), 5);
+        assert_eq!(
+            board.find_patterns_through(Move::new(2, 2), Side::X).len(),
+            1
+        );
+        assert!(board
+            .find_patterns_through(Move::new(7, 7), Side::X)
+            .is_empty());
+    }
+}
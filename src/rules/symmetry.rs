@@ -0,0 +1,177 @@
+//! The 8 symmetries of the (square) board
+use ndarray::Array3;
+use rand::Rng;
+
+use crate::constants::sizes;
+
+/// Symmetries of the board, in the same order as the augmentations in `scripts/model_trainer.py`.
+/// Rotations are counterclockwise (like `np.rot90`), flips are left-right (like `np.fliplr`)
+/// and happen before the rotation
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Symmetry {
+    Identity,
+    Rot90,
+    Rot180,
+    Rot270,
+    Flip,
+    FlipRot90,
+    FlipRot180,
+    FlipRot270,
+}
+
+impl Symmetry {
+    pub const ALL: [Symmetry; 8] = [
+        Symmetry::Identity,
+        Symmetry::Rot90,
+        Symmetry::Rot180,
+        Symmetry::Rot270,
+        Symmetry::Flip,
+        Symmetry::FlipRot90,
+        Symmetry::FlipRot180,
+        Symmetry::FlipRot270,
+    ];
+
+    pub fn random() -> Symmetry {
+        Symmetry::ALL[rand::thread_rng().gen_range(0..Symmetry::ALL.len())]
+    }
+
+    /// Whether it flips, and the number of quarter turns after that
+    fn parts(&self) -> (bool, usize) {
+        let i = Symmetry::ALL.iter().position(|s| s == self).unwrap();
+        (i >= 4, i % 4)
+    }
+
+    /// The symmetry that undoes this one
+    pub fn inverse(&self) -> Symmetry {
+        match self {
+            Symmetry::Rot90 => Symmetry::Rot270,
+            Symmetry::Rot270 => Symmetry::Rot90,
+            // the rest are their own inverse
+            _ => *self,
+        }
+    }
+
+    /// The symmetry doing `self` first and then `other`
+    pub fn then(&self, other: Symmetry) -> Symmetry {
+        // a symmetry is fully determined by where a corner and its neighbour end up
+        let image = |s: Symmetry| [s.apply(0, 0), s.apply(1, 0)];
+        let want = image(*self).map(|(x, y)| other.apply(x, y));
+        *Symmetry::ALL.iter().find(|&&s| image(s) == want).unwrap()
+    }
+
+    /// Where the square at row `y`, column `x` ends up, as (x, y)
+    pub fn apply(&self, x: usize, y: usize) -> (usize, usize) {
+        let n = sizes::BOARD_WIDTH;
+        let (flip, turns) = self.parts();
+        let (mut row, mut col) = (y, x);
+        if flip {
+            col = n - 1 - col;
+        }
+        for _ in 0..turns {
+            // np.rot90: out[i, j] = in[j, n - 1 - i]
+            (row, col) = (n - 1 - col, row);
+        }
+        (col, row)
+    }
+}
+
+/// Applies `symmetry` to every plane of a `[y, x, plane]` array,
+/// such as game state contents or policy planes
+pub fn transform_planes<T: Clone>(planes: &Array3<T>, symmetry: Symmetry) -> Array3<T> {
+    let mut res = planes.clone();
+    for ((y, x, p), value) in planes.indexed_iter() {
+        let (tx, ty) = symmetry.apply(x, y);
+        res[[ty, tx, p]] = value.clone();
+    }
+    res
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rules::types::{GameState, Move};
+
+    fn sample_game() -> GameState {
+        let mut game = GameState::init_game_state();
+        for (x, y) in [(1, 3), (8, 4), (2, 3), (0, 12), (5, 11)] {
+            game.move_game(Move::new(x, y), None);
+        }
+        game
+    }
+
+    #[test]
+    fn round_trips() {
+        let game = sample_game();
+        let policy = Array3::from_shape_fn(sizes::MOVE_SHAPE, |(y, x, _)| (y * 100 + x) as f32);
+        for symmetry in Symmetry::ALL {
+            let inverse = symmetry.inverse();
+            assert_eq!(symmetry.then(inverse), Symmetry::Identity);
+            assert_eq!(
+                game.transform(symmetry)
+                    .transform(inverse)
+                    .get_contents_clone(),
+                game.get_contents_clone()
+            );
+            assert_eq!(
+                transform_planes(&transform_planes(&policy, symmetry), inverse),
+                policy
+            );
+            let mv = Move::new(4, 9);
+            assert_eq!(mv.transform(symmetry).transform(inverse), mv);
+        }
+    }
+
+    #[test]
+    fn moves_follow_the_board() {
+        let game = sample_game();
+        let policy = Array3::from_shape_fn(sizes::MOVE_SHAPE, |(y, x, _)| (y * 100 + x) as f32);
+        for symmetry in Symmetry::ALL {
+            let transformed = game.transform(symmetry);
+            let transformed_policy = transform_planes(&policy, symmetry);
+            for mv in [Move::new(1, 3), Move::new(8, 4), Move::new(0, 12)] {
+                let tmv = mv.transform(symmetry);
+                assert_eq!(
+                    transformed.get_board_view().is_empty_grid(tmv.x, tmv.y),
+                    game.get_board_view().is_empty_grid(mv.x, mv.y)
+                );
+                assert_eq!(
+                    transformed_policy[tmv.get_move_arr()],
+                    policy[mv.get_move_arr()]
+                );
+            }
+            for other in Symmetry::ALL {
+                let mv = Move::new(2, 7);
+                assert_eq!(
+                    mv.transform(symmetry).transform(other),
+                    mv.transform(symmetry.then(other))
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn canonical_is_shared_by_symmetric_games() {
+        let game = sample_game();
+        let (canonical, symmetry) = game.canonical();
+        assert_eq!(
+            game.transform(symmetry).get_contents_clone(),
+            canonical.get_contents_clone()
+        );
+        for symmetry in Symmetry::ALL {
+            assert_eq!(
+                game.transform(symmetry).canonical().0.get_contents_clone(),
+                canonical.get_contents_clone()
+            );
+        }
+    }
+
+    #[test]
+    fn matches_numpy() {
+        // np.rot90(m, 1, (0, 1))[i, j] == m[j, n - 1 - i], np.fliplr(m)[i, j] == m[i, n - 1 - j]
+        let n = sizes::BOARD_WIDTH;
+        assert_eq!(Symmetry::Rot90.apply(0, 0), (0, n - 1));
+        assert_eq!(Symmetry::Rot90.apply(3, 1), (1, n - 4));
+        assert_eq!(Symmetry::Flip.apply(3, 1), (n - 4, 1));
+        assert_eq!(Symmetry::FlipRot90.apply(3, 1), (1, 3));
+    }
+}
@@ -0,0 +1,355 @@
+//! Threat-space search: finds victory by continuous fours (VCF) and
+//! victory by continuous threats (VCT) for the side to move.
+use std::time::{Duration, Instant};
+
+use ndarray::OwnedRepr;
+
+use crate::constants::{self, sizes};
+
+use super::patterns::{offset, Pattern, PatternKind, LINES};
+use super::types::{Board, GameState, Move, Side};
+
+/// Which threats the attacker is allowed to play
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ThreatKind {
+    /// Only fours, the defender's reply is always forced
+    Vcf,
+    /// Fours and threes
+    Vct,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct SearchLimits {
+    /// Maximum number of attacker moves in the winning line
+    pub max_depth: usize,
+    pub time_limit: Duration,
+}
+
+impl SearchLimits {
+    pub fn vcf() -> Self {
+        SearchLimits {
+            max_depth: constants::tactics::VCF_MAX_DEPTH,
+            time_limit: Duration::from_millis(constants::tactics::TIME_LIMIT_MS),
+        }
+    }
+    pub fn vct() -> Self {
+        SearchLimits {
+            max_depth: constants::tactics::VCT_MAX_DEPTH,
+            time_limit: Duration::from_millis(constants::tactics::TIME_LIMIT_MS),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum TacticalResult {
+    /// Winning line, starting with the attacker's move and alternating with the defender's replies
+    Win(Vec<Move>),
+    /// No win was found within `max_depth`
+    NoWin,
+    /// Ran out of time before finishing the search
+    Timeout,
+}
+
+impl TacticalResult {
+    pub fn is_win(&self) -> bool {
+        matches!(self, TacticalResult::Win(_))
+    }
+}
+
+/// Looks for a victory by continuous fours for the side to move
+pub fn find_vcf(game_state: &GameState, limits: SearchLimits) -> TacticalResult {
+    Solver::new(game_state, ThreatKind::Vcf, limits).solve(limits.max_depth)
+}
+
+/// Looks for a victory by continuous threats (fours and threes) for the side to move
+pub fn find_vct(game_state: &GameState, limits: SearchLimits) -> TacticalResult {
+    Solver::new(game_state, ThreatKind::Vct, limits).solve(limits.max_depth)
+}
+
+struct Solver {
+    board: Board<OwnedRepr<bool>>,
+    attacker: Side,
+    kind: ThreatKind,
+    deadline: Instant,
+    timed_out: bool,
+    line: Vec<Move>,
+    finished: bool,
+}
+
+impl Solver {
+    fn new(game_state: &GameState, kind: ThreatKind, limits: SearchLimits) -> Self {
+        Solver {
+            board: game_state.get_board_clone(),
+            attacker: game_state.get_side(),
+            kind,
+            deadline: Instant::now() + limits.time_limit,
+            timed_out: false,
+            line: Vec::new(),
+            finished: game_state.evaluate().has_ended(),
+        }
+    }
+
+    /// Iterative deepening, so the shortest win is found first
+    fn solve(mut self, max_depth: usize) -> TacticalResult {
+        if self.finished {
+            return TacticalResult::NoWin;
+        }
+        for depth in 0..=max_depth {
+            if self.attack(depth) {
+                return TacticalResult::Win(self.line);
+            }
+            if self.timed_out {
+                return TacticalResult::Timeout;
+            }
+        }
+        TacticalResult::NoWin
+    }
+
+    fn out_of_time(&mut self) -> bool {
+        if !self.timed_out && Instant::now() >= self.deadline {
+            self.timed_out = true;
+        }
+        self.timed_out
+    }
+
+    fn play(&mut self, mv: Move, side: Side) {
+        self.board.set_grid(mv.x, mv.y, side.plane_index(), true);
+    }
+
+    fn undo(&mut self, mv: Move, side: Side) {
+        self.board.set_grid(mv.x, mv.y, side.plane_index(), false);
+    }
+
+    /// Attacker to move, returns true (and extends `line`) if it wins within `depth` more moves
+    fn attack(&mut self, depth: usize) -> bool {
+        if self.out_of_time() {
+            return false;
+        }
+        let attacker = self.attacker;
+        let defender = attacker.opponent();
+
+        if let Some(&mv) = self.board.winning_moves(attacker).first() {
+            self.line.push(mv);
+            return true;
+        }
+        if depth == 0 {
+            return false;
+        }
+        // the defender has a four, so we have to block it
+        let blocks = self.board.winning_moves(defender);
+        let candidates = match blocks.len() {
+            0 => self.threat_moves(depth),
+            1 => blocks,
+            _ => return false,
+        };
+
+        for mv in candidates {
+            self.play(mv, attacker);
+            self.line.push(mv);
+            let won = self.defend(depth - 1);
+            self.undo(mv, attacker);
+            if won {
+                return true;
+            }
+            self.line.pop();
+        }
+        false
+    }
+
+    /// Defender to move, returns true if the attacker wins against every relevant reply.
+    /// `line` is extended with the longest defence found
+    fn defend(&mut self, depth: usize) -> bool {
+        if self.out_of_time() {
+            return false;
+        }
+        let attacker = self.attacker;
+        let defender = attacker.opponent();
+
+        if !self.board.winning_moves(defender).is_empty() {
+            return false;
+        }
+        let threats = self.board.winning_moves(attacker);
+        let attacker_threes: Vec<Pattern> = self
+            .board
+            .find_patterns(attacker)
+            .into_iter()
+            .filter(|p| is_open_three(p.kind))
+            .collect();
+        let replies = if let Some(&block) = threats.first() {
+            // blocking anything else loses immediately
+            vec![block]
+        } else if self.kind == ThreatKind::Vct && !attacker_threes.is_empty() {
+            // stop the threes from becoming fours, or counter with a four
+            let defender_threes = self.board.find_patterns(defender);
+            let mut replies = Vec::new();
+            for pattern in attacker_threes.iter().chain(defender_threes.iter()) {
+                if matches!(pattern.kind, PatternKind::Four | PatternKind::OpenFour) {
+                    continue;
+                }
+                for &mv in pattern.completions.iter() {
+                    if !replies.contains(&mv) {
+                        replies.push(mv);
+                    }
+                }
+            }
+            replies
+        } else {
+            // no threat, the defender is free to play anything
+            return false;
+        };
+
+        let start = self.line.len();
+        let mut longest: Vec<Move> = Vec::new();
+        for mv in replies {
+            self.play(mv, defender);
+            self.line.push(mv);
+            let won = self.attack(depth);
+            self.undo(mv, defender);
+            if !won {
+                self.line.truncate(start);
+                return false;
+            }
+            if self.line.len() - start > longest.len() {
+                longest = self.line[start..].to_vec();
+            }
+            self.line.truncate(start);
+        }
+        self.line.extend(longest);
+        true
+    }
+
+    /// Attacking moves allowed by `kind` that can still win within `depth` moves,
+    /// most threatening first
+    fn threat_moves(&mut self, depth: usize) -> Vec<Move> {
+        let attacker = self.attacker;
+        let mut scored = Vec::new();
+        for mv in empty_squares_near(&self.board, attacker) {
+            self.play(mv, attacker);
+            let patterns = self.board.find_patterns_through(mv, attacker);
+            let mut fives: Vec<Move> = Vec::new();
+            for pattern in patterns.iter() {
+                if matches!(pattern.kind, PatternKind::Four | PatternKind::OpenFour) {
+                    for &sq in pattern.completions.iter() {
+                        if !fives.contains(&sq) {
+                            fives.push(sq);
+                        }
+                    }
+                }
+            }
+            // a three needs 2 more attacker moves to become a five
+            let threes = if self.kind == ThreatKind::Vct && fives.is_empty() && depth >= 2 {
+                patterns.iter().filter(|p| is_open_three(p.kind)).count()
+            } else {
+                0
+            };
+            self.undo(mv, attacker);
+            // with a single move left, only an unstoppable four can win
+            let usable = if depth == 1 {
+                fives.len() >= 2
+            } else {
+                !fives.is_empty() || threes > 0
+            };
+            if usable {
+                scored.push((fives.len(), threes, mv));
+            }
+        }
+        scored.sort_by_key(|&(fives, threes, _)| std::cmp::Reverse((fives, threes)));
+        scored.into_iter().map(|(_, _, mv)| mv).collect()
+    }
+}
+
+/// Threes that become an open four with one more move
+fn is_open_three(kind: PatternKind) -> bool {
+    matches!(kind, PatternKind::OpenThree | PatternKind::BrokenThree)
+}
+
+/// Empty squares that share a line with a `side` piece close enough to be part of the same five
+fn empty_squares_near<T: ndarray::Data<Elem = bool>>(board: &Board<T>, side: Side) -> Vec<Move> {
+    let reach = sizes::NUM_IN_A_ROW_FOR_WIN as i8 - 1;
+    let mut res = Vec::new();
+    for y in 0..sizes::BOARD_HEIGHT {
+        for x in 0..sizes::BOARD_WIDTH {
+            if !board.is_empty_grid(x, y) {
+                continue;
+            }
+            let near = LINES.iter().any(|&dir| {
+                (-reach..=reach)
+                    .filter_map(|k| offset(x, y, dir, k))
+                    .any(|(px, py)| board.get_grid(px, py, side.plane_index()))
+            });
+            if near {
+                res.push(Move::new(x, y));
+            }
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Plays `x_moves` and `o_moves` alternately, X first
+    fn game_from_moves(x_moves: &[(usize, usize)], o_moves: &[(usize, usize)]) -> GameState {
+        let mut game = GameState::init_game_state();
+        for i in 0..x_moves.len().max(o_moves.len()) {
+            if let Some(&(x, y)) = x_moves.get(i) {
+                game.move_game(Move::new(x, y), Some(Side::X));
+            }
+            if let Some(&(x, y)) = o_moves.get(i) {
+                game.move_game(Move::new(x, y), Some(Side::O));
+            }
+        }
+        game
+    }
+
+    #[test]
+    fn finds_immediate_five() {
+        let game = game_from_moves(
+            &[(3, 5), (4, 5), (5, 5), (6, 5)],
+            &[(0, 0), (12, 0), (0, 12), (12, 12)],
+        );
+        match find_vcf(&game, SearchLimits::vcf()) {
+            TacticalResult::Win(line) => {
+                assert_eq!(line.len(), 1);
+                assert!(line[0] == Move::new(2, 5) || line[0] == Move::new(7, 5));
+            }
+            res => panic!("expected a win, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn finds_double_four_vcf() {
+        // two closed threes sharing the square (5, 2)
+        let game = game_from_moves(
+            &[(2, 2), (3, 2), (4, 2), (5, 3), (5, 4), (5, 5)],
+            &[(1, 2), (5, 6), (12, 12), (10, 12), (12, 10), (8, 12)],
+        );
+        match find_vcf(&game, SearchLimits::vcf()) {
+            TacticalResult::Win(line) => {
+                assert_eq!(line.len(), 3);
+                assert_eq!(line[0], Move::new(5, 2));
+            }
+            res => panic!("expected a win, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn finds_double_three_vct_but_not_vcf() {
+        let game = game_from_moves(
+            &[(4, 6), (5, 6), (6, 4), (6, 5)],
+            &[(0, 0), (12, 0), (0, 12), (12, 12)],
+        );
+        assert!(matches!(
+            find_vcf(&game, SearchLimits::vcf()),
+            TacticalResult::NoWin
+        ));
+        match find_vct(&game, SearchLimits::vct()) {
+            TacticalResult::Win(line) => {
+                assert_eq!(line[0], Move::new(6, 6));
+                assert!(line.len() >= 5);
+            }
+            res => panic!("expected a win, got {:?}", res),
+        }
+    }
+}
@@ -1,15 +1,14 @@
 use rand::Rng;
+use std::collections::hash_map::DefaultHasher;
 use std::fmt::{self, Display, Formatter};
+use std::hash::{Hash, Hasher};
 
 use ndarray::{s, Array3, ArrayBase, Axis, Data, DataMut, Dim, OwnedRepr, RawData, ViewRepr};
-use tensorflow::{
-    eager::{self, raw_ops, Context, ReadonlyTensor, ToTensorHandle},
-    Graph, Operation, SavedModelBundle, SessionOptions, SessionRunArgs, Tensor,
-    DEFAULT_SERVING_SIGNATURE_DEF_KEY,
-};
+use serde::{Deserialize, Serialize};
 
 use crate::constants::{self, sizes};
 
+use super::symmetry::{transform_planes, Symmetry};
 use super::{has_n_in_a_row_in_dir, DIRECTIONS};
 
 pub struct Coord3D {
@@ -32,7 +31,7 @@ impl Coord3D {
     }
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct Move {
     pub x: usize,
     pub y: usize,
@@ -62,6 +61,11 @@ impl Move {
     pub fn get_move_arr(&self) -> [usize; 3] {
         [self.y, self.x, self.p]
     }
+    /// The same move seen through `symmetry`, matches `GameState::transform`
+    pub fn transform(&self, symmetry: Symmetry) -> Move {
+        let (x, y) = symmetry.apply(self.x, self.y);
+        Move { x, y, p: self.p }
+    }
 }
 
 impl fmt::Display for Move {
@@ -75,7 +79,7 @@ impl fmt::Debug for Move {
     }
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum Side {
     X,
     O,
@@ -87,9 +91,22 @@ impl Side {
             Side::O => false,
         }
     }
+    pub fn opponent(&self) -> Side {
+        match self {
+            Side::X => Side::O,
+            Side::O => Side::X,
+        }
+    }
+    /// Index of the plane holding this side's pieces in a `Board`
+    pub fn plane_index(&self) -> usize {
+        match self {
+            Side::X => 0,
+            Side::O => 1,
+        }
+    }
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
 pub enum GameResult {
     XWins,
     OWins,
@@ -215,7 +232,7 @@ impl<T: Data + RawData<Elem = bool>> Board<T> {
     pub fn legal_moves_onehot(&self, side: Option<Side>) -> Array3<bool> {
         let mut res = Array3::from_elem(constants::sizes::MOVE_SHAPE, false);
         for mv in self.get_legal_moves(side) {
-            res[[mv.y, mv.x, 1]] = true;
+            res[mv.get_move_arr()] = true;
         }
         res
     }
@@ -282,6 +299,11 @@ impl GameState {
             contents: Array3::from_elem(sizes::GAME_STATE_SHAPE, false),
         }
     }
+    /// The game state stored as `contents`, like the ones in the training data
+    pub fn from_contents(contents: Array3<bool>) -> Self {
+        assert_eq!(contents.dim(), sizes::GAME_STATE_SHAPE);
+        GameState { contents }
+    }
 
     pub fn get_board_view(&self) -> Board<ViewRepr<&bool>> {
         Board {
@@ -344,9 +366,36 @@ impl GameState {
     pub fn get_contents_clone(&self) -> Array3<bool> {
         self.contents.to_owned()
     }
+    #[allow(clippy::should_implement_trait)]
     pub fn into_iter(self) -> impl Iterator<Item = bool> {
         self.contents.into_iter()
     }
+    /// The same game seen through `symmetry`
+    pub fn transform(&self, symmetry: Symmetry) -> GameState {
+        GameState {
+            contents: transform_planes(&self.contents, symmetry),
+        }
+    }
+    /// The smallest of the 8 symmetric versions of this game, and the symmetry that gives it.
+    /// Games that are the same up to symmetry have the same canonical form
+    pub fn canonical(&self) -> (GameState, Symmetry) {
+        Symmetry::ALL
+            .iter()
+            .map(|&symmetry| (self.transform(symmetry), symmetry))
+            .min_by(|(a, _), (b, _)| a.contents.iter().cmp(b.contents.iter()))
+            .unwrap()
+    }
+    /// Hash of the position (including the previous boards), used as a cache key
+    pub fn position_hash(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.contents.hash(&mut hasher);
+        hasher.finish()
+    }
+    pub fn get_board_clone(&self) -> Board<OwnedRepr<bool>> {
+        Board {
+            contents: self.get_board_view().get_contents_clone(),
+        }
+    }
     pub fn get_grid(&self, x: usize, y: usize, p: usize) -> bool {
         self.contents[[y, x, p]]
     }
@@ -377,103 +426,3 @@ impl GameState {
         self.get_board_view().legal_moves_onehot(side)
     }
 }
-pub struct NeuralNetOutput {
-    pub value_head: f32,
-    pub policy_head: ReadonlyTensor<f32>,
-}
-
-pub struct NeuralNet {
-    pub ctx: Context,
-    bundle: SavedModelBundle,
-    x_op: Operation,
-    value_head_op: Operation,
-    policy_head_op: Operation,
-}
-impl NeuralNet {
-    pub fn new() -> Self {
-        // Create an eager execution context
-        let opts = eager::ContextOptions::new();
-        let ctx = eager::Context::new(opts).unwrap();
-
-        // Load the model.
-        let mut graph = Graph::new();
-        let bundle = SavedModelBundle::load(
-            &SessionOptions::new(),
-            &["serve"],
-            &mut graph,
-            constants::model::NET_PATH,
-        )
-        .expect("Can't load saved model");
-
-        // get in/out operations
-        let signature = bundle
-            .meta_graph_def()
-            .get_signature(DEFAULT_SERVING_SIGNATURE_DEF_KEY)
-            .unwrap();
-
-        let x_info = signature.get_input("main_input").unwrap();
-        let x_op = graph
-            .operation_by_name_required(&x_info.name().name)
-            .unwrap();
-
-        let policy_head_info = signature
-            .get_output("policy_head")
-            .expect("Can't get policy head info");
-        let value_head_info = signature
-            .get_output("value_head")
-            .expect("Can't get value head info");
-
-        let policy_head_op = graph
-            .operation_by_name_required(&policy_head_info.name().name)
-            .expect("Can't get policy head op");
-        let value_head_op = graph
-            .operation_by_name_required(&value_head_info.name().name)
-            .expect("Can't get value head op");
-        NeuralNet {
-            bundle,
-            ctx,
-            x_op,
-            value_head_op,
-            policy_head_op,
-        }
-    }
-
-    pub fn run(&self, game_state: &GameState) -> NeuralNetOutput {
-        let bool_x = Tensor::from(game_state.get_contents_clone()).freeze();
-        let cast2float = raw_ops::Cast::new().DstT(tensorflow::DataType::Float);
-        let float_x = cast2float.call(&self.ctx, &bool_x).unwrap();
-        let batched_x = raw_ops::expand_dims(&self.ctx, &float_x, &0).unwrap();
-        let readonly_x = batched_x.resolve().unwrap();
-        let x: Tensor<f32> = unsafe { readonly_x.into_tensor() };
-
-        // Run the graph.
-        let mut args = SessionRunArgs::new();
-        args.add_feed(&self.x_op, 0, &x);
-
-        let value_head_token = args.request_fetch(&self.value_head_op, 0);
-        let policy_head_token = args.request_fetch(&self.policy_head_op, 0);
-
-        let result = self.bundle.session.run(&mut args);
-        if result.is_err() {
-            panic!("Error occured during calculations: {:?}", result);
-        }
-        let value_head_output: Tensor<f32> = args.fetch(value_head_token).unwrap();
-        let policy_head_output: Tensor<f32> = args.fetch(policy_head_token).unwrap();
-        let policy_head_output = raw_ops::reshape(
-            &self.ctx,
-            &policy_head_output.to_handle(&self.ctx).unwrap(),
-            &[
-                sizes::MOVE_SHAPE.0 as i32,
-                sizes::MOVE_SHAPE.1 as i32,
-                sizes::MOVE_SHAPE.2 as i32,
-            ],
-        )
-        .unwrap()
-        .resolve()
-        .unwrap();
-        NeuralNetOutput {
-            value_head: value_head_output[0],
-            policy_head: policy_head_output,
-        }
-    }
-}
@@ -0,0 +1,642 @@
+//! Training data stored as append-only shards.
+//! Every dump writes its games to a new numbered `.npz` in the data directory, in the compact
+//! encoding of `compact` (data converted from the old format keeps dense arrays), their records
+//! (see `game_record`) to a `.games.jsonl` of the same name, and adds a line
+//! `<file> <positions>` to the index, so what was written before is never read or rewritten.
+//! Files are written to a temporary file and renamed once complete, and a shard only counts once
+//! it's in the index, so a crash leaves at worst stray files, which `recover` cleans up.
+//! `scripts/training_data.py` reads the same index and the dense shards, and has the compact ones
+//! expanded by `cargo run -- export-batch --shard <file>`
+use std::fmt::{self, Display, Formatter};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use ndarray::{Array1, Array4, Axis};
+use ndarray_npy::{read_npy, NpzReader, NpzWriter, ReadNpyError, ReadNpzError, WriteNpzError};
+
+use crate::compact::{self, CompactGames};
+use crate::game_record::{self, GameRecord};
+use crate::types::ReplayData;
+
+pub const INDEX_FILE: &str = "index.txt";
+// names of the arrays in a dense shard, numpy looks them up without the extension
+const GAME_STATES: &str = "game_states.npy";
+const PIS: &str = "pis.npy";
+const OUTCOMES: &str = "outcomes.npy";
+// not in the shards written before the root values were recorded
+const ROOT_VALUES: &str = "root_values.npy";
+// files of the old format, where every dump rewrote the whole data
+const LEGACY_FILES: [&str; 3] = ["game_state_data.npy", "pi_data.npy", "result_data.npy"];
+const TEMP_EXTENSION: &str = ".tmp";
+const RECORDS_EXTENSION: &str = ".games.jsonl";
+
+/// Why the training data couldn't be read or written
+#[derive(Debug)]
+pub enum ShardError {
+    Io(io::Error),
+    Read(String),
+    Write(WriteNpzError),
+    /// The index or a shard doesn't hold what it should
+    Corrupt(String),
+    /// A game to store has no result
+    Unfinished(String),
+}
+
+impl Display for ShardError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ShardError::Io(e) => write!(f, "can't access training data: {}", e),
+            ShardError::Read(e) => write!(f, "can't read training data: {}", e),
+            ShardError::Write(e) => write!(f, "can't write training data: {}", e),
+            ShardError::Corrupt(e) => write!(f, "training data is corrupt: {}", e),
+            ShardError::Unfinished(e) => write!(f, "can't store an unfinished game: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ShardError {}
+
+impl From<io::Error> for ShardError {
+    fn from(e: io::Error) -> Self {
+        ShardError::Io(e)
+    }
+}
+impl From<ReadNpzError> for ShardError {
+    fn from(e: ReadNpzError) -> Self {
+        ShardError::Read(e.to_string())
+    }
+}
+impl From<ReadNpyError> for ShardError {
+    fn from(e: ReadNpyError) -> Self {
+        ShardError::Read(e.to_string())
+    }
+}
+impl From<WriteNpzError> for ShardError {
+    fn from(e: WriteNpzError) -> Self {
+        ShardError::Write(e)
+    }
+}
+
+/// An entry of the index
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ShardInfo {
+    /// File name of the shard, in the data directory
+    pub file: String,
+    /// Number of positions in the shard
+    pub len: usize,
+}
+
+/// The shards in the data directory `dir`, oldest first.
+/// Data in the old single-file format is converted to a first shard
+pub fn read_index(dir: &Path) -> Result<Vec<ShardInfo>, ShardError> {
+    let index_path = dir.join(INDEX_FILE);
+    if !index_path.exists() {
+        return migrate_legacy(dir);
+    }
+    let mut shards = Vec::new();
+    for (i, line) in BufReader::new(File::open(&index_path)?).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let corrupt = || ShardError::Corrupt(format!("line {} of the index: '{}'", i + 1, line));
+        let (file, len) = line.trim().split_once(' ').ok_or_else(corrupt)?;
+        shards.push(ShardInfo {
+            file: file.to_string(),
+            len: len.parse().map_err(|_| corrupt())?,
+        });
+    }
+    Ok(shards)
+}
+
+/// Creates the data directory `dir` with an empty index if it's missing.
+/// Data in the old single-file format is converted instead
+pub fn init(dir: &Path) -> Result<(), ShardError> {
+    fs::create_dir_all(dir)?;
+    if !dir.join(INDEX_FILE).exists() {
+        let shards = read_index(dir)?;
+        write_index(dir, &shards)?;
+    }
+    Ok(())
+}
+
+/// Writes the finished games of `records` to a new shard in `dir`, and adds it to the index.
+/// The positions are stored as the moves and visit counts of the games (see `compact`)
+pub fn write_shard(dir: &Path, records: &[GameRecord]) -> Result<ShardInfo, ShardError> {
+    check_finished(records)?;
+    let games = CompactGames::encode(records);
+    append_shard(dir, games.len(), records, |npz| games.write(npz))
+}
+
+/// Fails on the first game of `records` without a result, whose positions have no outcome
+pub fn check_finished(records: &[GameRecord]) -> Result<(), ShardError> {
+    match records.iter().position(|record| !record.result.has_ended()) {
+        Some(i) => Err(ShardError::Unfinished(format!(
+            "game {} of {} ({} moves)",
+            i + 1,
+            records.len(),
+            records[i].turns.len()
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Writes `data` to a new shard in `dir` as dense arrays, for positions that don't come with their games
+pub fn write_dense_shard(dir: &Path, data: &ReplayData) -> Result<ShardInfo, ShardError> {
+    append_shard(dir, data.len(), &[], |npz| write_dense(npz, data))
+}
+
+/// Writes `data` as dense arrays to the file `path`, outside of the index: the training batch
+/// `scripts/model_trainer.py` reads, as it can't expand the compact shards
+pub fn write_batch(path: &Path, data: &ReplayData) -> Result<(), ShardError> {
+    write_npz(path, |npz| write_dense(npz, data))
+}
+
+/// Writes the arrays of `write_arrays` to the compressed `.npz` at `path`, atomically
+fn write_npz<F>(path: &Path, write_arrays: F) -> Result<(), ShardError>
+where
+    F: FnOnce(&mut NpzWriter<&mut File>) -> Result<(), WriteNpzError>,
+{
+    write_atomically(path, |file| {
+        let mut npz = NpzWriter::new_compressed(file);
+        write_arrays(&mut npz)?;
+        npz.finish()?;
+        Ok(())
+    })
+}
+
+fn write_dense<W: Write + io::Seek>(
+    npz: &mut NpzWriter<W>,
+    data: &ReplayData,
+) -> Result<(), WriteNpzError> {
+    npz.add_array(GAME_STATES, &data.game_states)?;
+    npz.add_array(PIS, &data.pis)?;
+    npz.add_array(OUTCOMES, &data.outcomes)?;
+    npz.add_array(ROOT_VALUES, &data.root_values)?;
+    Ok(())
+}
+
+/// Writes a new shard of `len` positions with `write_arrays`, then the `records` of its games,
+/// and adds it to the index
+fn append_shard<F>(
+    dir: &Path,
+    len: usize,
+    records: &[GameRecord],
+    write_arrays: F,
+) -> Result<ShardInfo, ShardError>
+where
+    F: FnOnce(&mut NpzWriter<&mut File>) -> Result<(), WriteNpzError>,
+{
+    init(dir)?;
+    let mut shards = read_index(dir)?;
+    let shard = ShardInfo {
+        file: shard_file(shards.len()),
+        len,
+    };
+    write_npz(&dir.join(&shard.file), write_arrays)?;
+    if !records.is_empty() {
+        write_atomically(&records_path(dir, &shard), |file| {
+            let mut writer = BufWriter::new(file);
+            game_record::write_records(&mut writer, records)?;
+            writer.flush()?;
+            Ok(())
+        })?;
+    }
+    shards.push(shard.clone());
+    write_index(dir, &shards)?;
+    Ok(shard)
+}
+
+/// Replaces the index of `dir` with `shards`
+fn write_index(dir: &Path, shards: &[ShardInfo]) -> Result<(), ShardError> {
+    write_atomically(&dir.join(INDEX_FILE), |file| {
+        for shard in shards {
+            writeln!(file, "{} {}", shard.file, shard.len)?;
+        }
+        Ok(())
+    })
+}
+
+/// Writes the file at `path` with `write` through a temporary file, which replaces it once it's on disk,
+/// so readers see either the old file or the whole new one
+fn write_atomically<F>(path: &Path, write: F) -> Result<(), ShardError>
+where
+    F: FnOnce(&mut File) -> Result<(), ShardError>,
+{
+    let temp = temp_path(path);
+    let mut file = File::create(&temp)?;
+    write(&mut file)?;
+    file.sync_all()?;
+    fs::rename(&temp, path)?;
+    sync_parent(path)?;
+    Ok(())
+}
+
+/// Flushes the directory holding `path`, so a rename into it survives a crash
+#[cfg(unix)]
+fn sync_parent(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => File::open(dir)?.sync_all(),
+        _ => File::open(".")?.sync_all(),
+    }
+}
+
+/// Directories can't be opened as files to flush them outside of unix
+#[cfg(not(unix))]
+fn sync_parent(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// File name of the `i`th shard
+fn shard_file(i: usize) -> String {
+    format!("shard-{:06}.npz", i)
+}
+
+/// Where the records of the games of `shard` are
+fn records_path(dir: &Path, shard: &ShardInfo) -> PathBuf {
+    let stem = shard.file.strip_suffix(".npz").unwrap_or(&shard.file);
+    dir.join(format!("{stem}{RECORDS_EXTENSION}"))
+}
+
+fn temp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(TEMP_EXTENSION);
+    path.with_file_name(name)
+}
+
+/// What `recover` had to undo
+#[derive(Default, PartialEq, Eq, Debug)]
+pub struct Recovery {
+    /// Shards that were in the index, but are missing or unreadable, with all the ones after them
+    pub dropped_shards: Vec<ShardInfo>,
+    /// Temporary files and shards that never made it to the index,
+    /// and the files of the old format left over by a conversion
+    pub removed_files: Vec<String>,
+}
+
+impl Recovery {
+    pub fn is_clean(&self) -> bool {
+        self.dropped_shards.is_empty() && self.removed_files.is_empty()
+    }
+}
+
+/// Brings the data directory `dir` back to its last consistent state after a crash:
+/// truncates the index before the first shard that can't be opened, and removes the files it doesn't list
+pub fn recover(dir: &Path) -> Result<Recovery, ShardError> {
+    let mut recovery = Recovery::default();
+    if !dir.is_dir() {
+        return Ok(recovery);
+    }
+    let mut shards = read_index(dir)?;
+    if let Some(first_bad) = shards.iter().position(|shard| !is_complete(dir, shard)) {
+        recovery.dropped_shards = shards.split_off(first_bad);
+        write_index(dir, &shards)?;
+    }
+    // the conversion of the old format stopped before removing its files
+    if let Some(data) = read_legacy(dir)? {
+        let converted = shards
+            .first()
+            .is_some_and(|shard| shard.len == data.len() && !records_path(dir, shard).exists());
+        if !converted && !data.is_empty() {
+            shards.push(write_dense_shard(dir, &data)?);
+        }
+        for file in LEGACY_FILES {
+            fs::remove_file(dir.join(file))?;
+            recovery.removed_files.push(file.to_string());
+        }
+    }
+
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        let is_temp = name.ends_with(TEMP_EXTENSION);
+        let is_stray_shard = name.starts_with("shard-")
+            && (name.ends_with(".npz") || name.ends_with(RECORDS_EXTENSION))
+            && !shards
+                .iter()
+                .any(|shard| shard.file == name || records_path(dir, shard).ends_with(&name));
+        if is_temp || is_stray_shard {
+            fs::remove_file(dir.join(&name))?;
+            recovery.removed_files.push(name);
+        }
+    }
+    recovery.removed_files.sort();
+    Ok(recovery)
+}
+
+/// Whether the file of `shard` is there and holds all its arrays
+fn is_complete(dir: &Path, shard: &ShardInfo) -> bool {
+    let names = File::open(dir.join(&shard.file))
+        .map_err(ShardError::from)
+        .and_then(|file| Ok(NpzReader::new(file)?.names()?));
+    match names {
+        Ok(names) => {
+            let has = |array: &&str| names.iter().any(|name| name == array);
+            [GAME_STATES, PIS, OUTCOMES].iter().all(has)
+                || [compact::MOVES, compact::OUTCOMES].iter().all(has)
+        }
+        Err(_) => false,
+    }
+}
+
+/// Reads the positions of `shard`, in the data directory `dir`
+pub fn read_shard(dir: &Path, shard: &ShardInfo) -> Result<ReplayData, ShardError> {
+    let mut npz = NpzReader::new(File::open(dir.join(&shard.file))?)?;
+    if npz.names()?.iter().any(|name| name == compact::MOVES) {
+        let data = CompactGames::read(&mut npz)?
+            .decode()
+            .map_err(|e| ShardError::Corrupt(format!("{}: {}", shard.file, e)))?;
+        if data.len() != shard.len {
+            return Err(ShardError::Corrupt(format!(
+                "{} should hold {} positions, but has {}",
+                shard.file,
+                shard.len,
+                data.len()
+            )));
+        }
+        return Ok(data);
+    }
+    let game_states: Array4<bool> = npz.by_name(GAME_STATES)?;
+    let pis: Array4<f32> = npz.by_name(PIS)?;
+    let outcomes: Array1<f32> = npz.by_name(OUTCOMES)?;
+    let root_values: Array1<f32> = if npz.names()?.iter().any(|name| name == ROOT_VALUES) {
+        npz.by_name(ROOT_VALUES)?
+    } else {
+        outcomes.clone()
+    };
+    let lens = [
+        game_states.len_of(Axis(0)),
+        pis.len_of(Axis(0)),
+        outcomes.len(),
+        root_values.len(),
+    ];
+    if lens.iter().any(|&len| len != shard.len) {
+        return Err(ShardError::Corrupt(format!(
+            "{} should hold {} positions, but has {} game states, {} pis, {} results and {} root values",
+            shard.file, shard.len, lens[0], lens[1], lens[2], lens[3]
+        )));
+    }
+    Ok(ReplayData {
+        game_states,
+        pis,
+        outcomes,
+        root_values,
+    })
+}
+
+/// Reads the records of the games of `shard`, there are none for data converted from the old format
+pub fn read_records(dir: &Path, shard: &ShardInfo) -> Result<Vec<GameRecord>, ShardError> {
+    match File::open(records_path(dir, shard)) {
+        Ok(file) => Ok(game_record::read_records(BufReader::new(file))?),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Reads the shards of a data directory one at a time
+pub struct ShardReader {
+    dir: PathBuf,
+    shards: std::vec::IntoIter<ShardInfo>,
+}
+
+impl ShardReader {
+    /// Reads all the shards in `dir`, oldest first
+    pub fn new(dir: &Path) -> Result<Self, ShardError> {
+        Ok(Self::from_shards(dir, read_index(dir)?))
+    }
+
+    /// Reads `shards`, from the data directory `dir`
+    pub fn from_shards(dir: &Path, shards: Vec<ShardInfo>) -> Self {
+        ShardReader {
+            dir: dir.to_path_buf(),
+            shards: shards.into_iter(),
+        }
+    }
+}
+
+impl Iterator for ShardReader {
+    type Item = Result<ReplayData, ShardError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let shard = self.shards.next()?;
+        Some(read_shard(&self.dir, &shard))
+    }
+}
+
+/// Converts the data of the old format in `dir`, if any, to the first shard.
+/// The index is written once the shard is complete, and the old files are removed last,
+/// so a crash before the index is written converts them again, and `recover` removes them after
+fn migrate_legacy(dir: &Path) -> Result<Vec<ShardInfo>, ShardError> {
+    let data = match read_legacy(dir)? {
+        Some(data) => data,
+        None => return Ok(Vec::new()),
+    };
+    let mut shards = Vec::new();
+    if !data.is_empty() {
+        let shard = ShardInfo {
+            file: shard_file(0),
+            len: data.len(),
+        };
+        write_npz(&dir.join(&shard.file), |npz| write_dense(npz, &data))?;
+        shards.push(shard);
+    }
+    write_index(dir, &shards)?;
+    for file in LEGACY_FILES {
+        fs::remove_file(dir.join(file))?;
+    }
+    Ok(shards)
+}
+
+/// The data of the old format in `dir`, if it's there
+fn read_legacy(dir: &Path) -> Result<Option<ReplayData>, ShardError> {
+    let [game_states, pis, outcomes] = LEGACY_FILES.map(|file| dir.join(file));
+    if !game_states.exists() {
+        return Ok(None);
+    }
+    let outcomes: Array1<f32> = read_npy(outcomes)?;
+    let data = ReplayData {
+        game_states: read_npy(game_states)?,
+        pis: read_npy(pis)?,
+        root_values: outcomes.clone(),
+        outcomes,
+    };
+    if data.game_states.len_of(Axis(0)) != data.len() || data.pis.len_of(Axis(0)) != data.len() {
+        return Err(ShardError::Corrupt(
+            "the old training data files have different lengths".into(),
+        ));
+    }
+    Ok(Some(data))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rules::types::GameResult;
+    use crate::test_util::{self, TempDir};
+    use ndarray_npy::write_npy;
+
+    /// The record of the first `len` moves of a seeded game
+    fn record(len: usize, seed: u64) -> GameRecord {
+        let mut record = test_util::play(len, seed, true);
+        record.finish(GameResult::XWins);
+        record
+    }
+
+    fn replay_data(len: usize, outcome: f32) -> ReplayData {
+        ReplayData {
+            game_states: Array4::from_shape_fn((len, 13, 13, 9), |(i, y, x, _)| x + y == i),
+            pis: Array4::from_elem((len, 13, 13, 1), 1.0 / 169.0),
+            outcomes: Array1::from_elem(len, outcome),
+            root_values: Array1::from_elem(len, outcome / 2.0),
+        }
+    }
+
+    #[test]
+    fn shards_test() {
+        let dir = TempDir::new("shards");
+        assert!(read_index(&dir).unwrap().is_empty());
+
+        write_dense_shard(&dir, &replay_data(3, 1.0)).unwrap();
+        let records = [record(1, 1), record(1, 2)];
+        write_shard(&dir, &records).unwrap();
+        let shards = read_index(&dir).unwrap();
+        assert_eq!(
+            shards,
+            vec![
+                ShardInfo {
+                    file: "shard-000000.npz".into(),
+                    len: 3
+                },
+                ShardInfo {
+                    file: "shard-000001.npz".into(),
+                    len: 2
+                },
+            ]
+        );
+        let read: Vec<ReplayData> = ShardReader::new(&dir)
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(read.len(), 2);
+        assert_eq!(read[0].game_states, replay_data(3, 1.0).game_states);
+        // both games start from the empty board, where X is to move
+        assert_eq!(read[1].outcomes.to_vec(), vec![1.0, 1.0]);
+        assert_eq!(
+            read[1].root_values.to_vec(),
+            vec![
+                records[0].turns[0].root_value,
+                records[1].turns[0].root_value
+            ]
+        );
+        assert!(read_records(&dir, &shards[0]).unwrap().is_empty());
+        assert_eq!(read_records(&dir, &shards[1]).unwrap(), records);
+
+        // the index has to agree with the shards
+        let wrong = ShardInfo {
+            file: "shard-000001.npz".into(),
+            len: 5,
+        };
+        assert!(matches!(
+            read_shard(&dir, &wrong),
+            Err(ShardError::Corrupt(_))
+        ));
+    }
+
+    #[test]
+    fn recover_test() {
+        let dir = TempDir::new("recover");
+        for len in [1, 2, 3] {
+            write_shard(&dir, &[record(len, len as u64)]).unwrap();
+        }
+        assert!(recover(&dir).unwrap().is_clean());
+
+        // crashed while writing a shard, after writing one without adding it to the index,
+        // and the second shard got lost
+        File::create(dir.join("shard-000003.npz.tmp")).unwrap();
+        fs::copy(dir.join("shard-000000.npz"), dir.join("shard-000004.npz")).unwrap();
+        fs::write(dir.join("shard-000001.npz"), b"not a zip").unwrap();
+
+        let recovery = recover(&dir).unwrap();
+        assert_eq!(
+            recovery
+                .dropped_shards
+                .iter()
+                .map(|shard| shard.len)
+                .collect::<Vec<_>>(),
+            vec![2, 3]
+        );
+        assert_eq!(
+            recovery.removed_files,
+            vec![
+                "shard-000001.games.jsonl",
+                "shard-000001.npz",
+                "shard-000002.games.jsonl",
+                "shard-000002.npz",
+                "shard-000003.npz.tmp",
+                "shard-000004.npz"
+            ]
+        );
+        let shards = read_index(&dir).unwrap();
+        assert_eq!(shards.len(), 1);
+        assert_eq!(read_shard(&dir, &shards[0]).unwrap().len(), 1);
+        assert_eq!(read_records(&dir, &shards[0]).unwrap().len(), 1);
+        assert!(recover(&dir).unwrap().is_clean());
+
+        // appending carries on after the consistent part
+        assert_eq!(
+            write_dense_shard(&dir, &replay_data(2, 1.0)).unwrap().file,
+            "shard-000001.npz"
+        );
+    }
+
+    #[test]
+    fn init_test() {
+        let root = TempDir::new("init");
+        let dir = root.join("training_data");
+        assert!(recover(&dir).unwrap().is_clean());
+        // a fresh checkout has no data directory
+        write_dense_shard(&dir, &replay_data(2, 0.0)).unwrap();
+        assert_eq!(read_index(&dir).unwrap().len(), 1);
+        init(&dir).unwrap();
+        assert_eq!(read_index(&dir).unwrap().len(), 1);
+
+        let empty = dir.with_file_name("empty");
+        init(&empty).unwrap();
+        assert!(empty.join(INDEX_FILE).exists());
+        assert!(read_index(&empty).unwrap().is_empty());
+    }
+
+    #[test]
+    fn migrate_legacy_test() {
+        let dir = TempDir::new("legacy");
+        let data = replay_data(4, 0.0);
+        let write_legacy = |data: &ReplayData| {
+            write_npy(dir.join(LEGACY_FILES[0]), &data.game_states).unwrap();
+            write_npy(dir.join(LEGACY_FILES[1]), &data.pis).unwrap();
+            write_npy(dir.join(LEGACY_FILES[2]), &data.outcomes).unwrap();
+        };
+        write_legacy(&data);
+
+        let shards = read_index(&dir).unwrap();
+        assert_eq!(shards.len(), 1);
+        assert_eq!(read_shard(&dir, &shards[0]).unwrap().pis, data.pis);
+        assert!(!dir.join(LEGACY_FILES[0]).exists());
+        assert_eq!(read_index(&dir).unwrap(), shards);
+
+        // crashed after writing the index, before removing the old files
+        write_legacy(&data);
+        let recovery = recover(&dir).unwrap();
+        assert_eq!(recovery.removed_files, LEGACY_FILES.to_vec());
+        assert_eq!(read_index(&dir).unwrap(), shards);
+        assert!(!dir.join(LEGACY_FILES[2]).exists());
+
+        // an index written before the old data was converted doesn't orphan it
+        write_index(&dir, &[]).unwrap();
+        fs::remove_file(dir.join(&shards[0].file)).unwrap();
+        write_legacy(&data);
+        recover(&dir).unwrap();
+        assert_eq!(read_index(&dir).unwrap(), shards);
+        assert_eq!(read_shard(&dir, &shards[0]).unwrap().pis, data.pis);
+        assert!(recover(&dir).unwrap().is_clean());
+    }
+}
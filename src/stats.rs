@@ -0,0 +1,245 @@
+//! Statistics of the training data (`cargo run -- stats`), and merging of the positions that were
+//! played several times, mostly in the opening
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{self, Display, Formatter};
+
+use ndarray::{Array1, Array4, Axis};
+
+use crate::constants::sizes;
+use crate::game_record::GameRecord;
+use crate::rules::types::GameResult;
+use crate::types::ReplayData;
+
+// games are counted in buckets of this many moves
+const GAME_LENGTH_BUCKET: usize = 10;
+// policies are counted in buckets of this many nats of entropy
+const ENTROPY_BUCKET: f32 = 0.5;
+
+/// What `cargo run -- stats` reports
+#[derive(PartialEq, Debug)]
+pub struct DataStats {
+    pub positions: usize,
+    pub unique_positions: usize,
+    /// Number of times the most repeated position appears
+    pub max_repeats: usize,
+    /// Number of games in every bucket of `GAME_LENGTH_BUCKET` moves, by the first length of the bucket
+    pub game_lengths: BTreeMap<usize, usize>,
+    pub x_wins: usize,
+    pub o_wins: usize,
+    pub draws: usize,
+    /// Number of policies in every bucket of `ENTROPY_BUCKET` nats, by bucket
+    pub entropies: BTreeMap<usize, usize>,
+    pub mean_entropy: f32,
+}
+
+impl DataStats {
+    /// Statistics of the positions `data`, and of the games `records` they come from.
+    /// Data converted from the old format has no records, so its games aren't counted
+    pub fn new(data: &ReplayData, records: &[GameRecord]) -> Self {
+        let groups = duplicate_groups(data);
+        let mut stats = DataStats {
+            positions: data.len(),
+            unique_positions: groups.len(),
+            max_repeats: groups.iter().map(Vec::len).max().unwrap_or(0),
+            game_lengths: BTreeMap::new(),
+            x_wins: 0,
+            o_wins: 0,
+            draws: 0,
+            entropies: BTreeMap::new(),
+            mean_entropy: 0.0,
+        };
+        for record in records {
+            let bucket = record.turns.len() / GAME_LENGTH_BUCKET * GAME_LENGTH_BUCKET;
+            *stats.game_lengths.entry(bucket).or_default() += 1;
+            match record.result {
+                GameResult::XWins => stats.x_wins += 1,
+                GameResult::OWins => stats.o_wins += 1,
+                GameResult::Draws => stats.draws += 1,
+                GameResult::NotFinished => {}
+            }
+        }
+        let mut total_entropy = 0.0;
+        for pi in data.pis.axis_iter(Axis(0)) {
+            let entropy = policy_entropy(pi.iter());
+            total_entropy += entropy;
+            *stats
+                .entropies
+                .entry((entropy / ENTROPY_BUCKET) as usize)
+                .or_default() += 1;
+        }
+        if !data.is_empty() {
+            stats.mean_entropy = total_entropy / data.len() as f32;
+        }
+        stats
+    }
+
+    pub fn num_games(&self) -> usize {
+        self.game_lengths.values().sum()
+    }
+}
+
+impl Display for DataStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let percent = |count: usize, total: usize| 100.0 * count as f32 / total.max(1) as f32;
+        writeln!(
+            f,
+            "{} positions, {} unique ({:.1}%), the most repeated appears {} times",
+            self.positions,
+            self.unique_positions,
+            percent(self.unique_positions, self.positions),
+            self.max_repeats
+        )?;
+        let games = self.num_games();
+        writeln!(
+            f,
+            "{} recorded games: X wins {:.1}%, O wins {:.1}%, draws {:.1}%",
+            games,
+            percent(self.x_wins, games),
+            percent(self.o_wins, games),
+            percent(self.draws, games)
+        )?;
+        writeln!(f, "Game lengths:")?;
+        for (&start, &count) in &self.game_lengths {
+            let range = format!("{}-{}", start, start + GAME_LENGTH_BUCKET - 1);
+            writeln!(
+                f,
+                "{:>9} {:>7} {}",
+                range,
+                count,
+                bar(percent(count, games))
+            )?;
+        }
+        writeln!(f, "Policy entropy (mean {:.2} nats):", self.mean_entropy)?;
+        for (&bucket, &count) in &self.entropies {
+            let start = bucket as f32 * ENTROPY_BUCKET;
+            let range = format!("{:.1}-{:.1}", start, start + ENTROPY_BUCKET);
+            writeln!(
+                f,
+                "{:>9} {:>7} {}",
+                range,
+                count,
+                bar(percent(count, self.positions))
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// A bar of one `#` per 2%
+fn bar(percent: f32) -> String {
+    "#".repeat((percent / 2.0).round() as usize)
+}
+
+/// Entropy of a policy, in nats. The pis are visit counts raised to a power, so they're normalized first
+fn policy_entropy<'a, I: Iterator<Item = &'a f32> + Clone>(pi: I) -> f32 {
+    let sum: f32 = pi.clone().sum();
+    if sum <= 0.0 {
+        return 0.0;
+    }
+    -pi.filter(|&&p| p > 0.0)
+        .map(|&p| {
+            let p = p / sum;
+            p * p.ln()
+        })
+        .sum::<f32>()
+}
+
+/// The indices of the positions of `data`, grouped by game state, in the order they first appear
+fn duplicate_groups(data: &ReplayData) -> Vec<Vec<usize>> {
+    let mut groups: Vec<Vec<usize>> = Vec::new();
+    let mut group_of: HashMap<Vec<u8>, usize> = HashMap::new();
+    for (i, game_state) in data.game_states.axis_iter(Axis(0)).enumerate() {
+        // the planes packed 8 to a byte
+        let mut key = vec![0u8; game_state.len().div_ceil(8)];
+        for (bit, _) in game_state.iter().enumerate().filter(|(_, &b)| b) {
+            key[bit / 8] |= 1 << (bit % 8);
+        }
+        let group = *group_of.entry(key).or_insert_with(|| {
+            groups.push(Vec::new());
+            groups.len() - 1
+        });
+        groups[group].push(i);
+    }
+    groups
+}
+
+/// Merges the positions of `data` that have the same game state into one,
+/// with the mean of their pis, outcomes and root values
+pub fn merge_duplicates(data: &ReplayData) -> ReplayData {
+    let groups = duplicate_groups(data);
+    let mut pis = Array4::zeros((
+        groups.len(),
+        sizes::MOVE_WIDTH,
+        sizes::MOVE_HEIGHT,
+        sizes::MOVE_PLANES,
+    ));
+    let mut outcomes = Array1::zeros(groups.len());
+    let mut root_values = Array1::zeros(groups.len());
+    for (merged, group) in groups.iter().enumerate() {
+        let count = group.len() as f32;
+        let mut pi = pis.index_axis_mut(Axis(0), merged);
+        for &i in group {
+            pi += &data.pis.index_axis(Axis(0), i);
+            outcomes[merged] += data.outcomes[i];
+            root_values[merged] += data.root_values[i];
+        }
+        pi /= count;
+        outcomes[merged] /= count;
+        root_values[merged] /= count;
+    }
+    let firsts: Vec<usize> = groups.iter().map(|group| group[0]).collect();
+    ReplayData {
+        game_states: data.game_states.select(Axis(0), &firsts),
+        pis,
+        outcomes,
+        root_values,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::first_row_game;
+    use crate::types::TrainingData;
+
+    /// A game that plays `moves` on the first row, with pi all on the move played
+    fn game(moves: &[usize], result: GameResult) -> (TrainingData, GameRecord) {
+        let mut data = TrainingData::new();
+        let record = first_row_game(moves, result);
+        data.add_record(record.clone());
+        (data, record)
+    }
+
+    #[test]
+    fn stats_test() {
+        let (mut data, first) = game(&[0, 1, 2], GameResult::XWins);
+        let (second_data, second) = game(&[1, 2], GameResult::Draws);
+        data.append_game(second_data);
+        let data = data.into_replay_data().unwrap();
+
+        let stats = DataStats::new(&data, &[first, second]);
+        // both games start from the empty board
+        assert_eq!(stats.positions, 5);
+        assert_eq!(stats.unique_positions, 4);
+        assert_eq!(stats.max_repeats, 2);
+        assert_eq!(stats.game_lengths, BTreeMap::from([(0, 2)]));
+        assert_eq!((stats.x_wins, stats.o_wins, stats.draws), (1, 0, 1));
+        // every pi is a single move
+        assert_eq!(stats.entropies, BTreeMap::from([(0, 5)]));
+        assert!(stats.to_string().contains("4 unique (80.0%)"));
+
+        let merged = merge_duplicates(&data);
+        assert_eq!(merged.len(), 4);
+        // the empty board was answered once by 0 and once by 1
+        assert_eq!(merged.pis[[0, 0, 0, 0]], 0.5);
+        assert_eq!(merged.pis[[0, 0, 1, 0]], 0.5);
+        assert_eq!(merged.outcomes[0], 0.5);
+        assert_eq!(merged.outcomes[1], -1.0);
+        assert_eq!(
+            merged.game_states.index_axis(Axis(0), 3),
+            data.game_states.index_axis(Axis(0), 4)
+        );
+
+        assert!((policy_entropy([0.5, 0.5, 0.0].iter()) - 2f32.ln()).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,92 @@
+//! Fixtures shared by the tests: scratch directories and games to store in them
+use std::fs;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+use crate::evaluator::{Evaluator, UniformEvaluator};
+use crate::game_record::{GameRecord, SearchSettings, TurnRecord};
+use crate::monte_carlo_tree_search::{MCTSOutput, TreeSearch};
+use crate::rules::types::{GameResult, GameState, Move};
+
+/// A directory of the system's temporary directory, removed with everything in it when dropped,
+/// so a failed assert doesn't leave it behind
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    /// An empty directory named after `name` and the test process
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("nn5_{}_{}", name, std::process::id()));
+        // left over by a run that was killed
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TempDir {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+/// Plays the first `len` moves of a game seeded with `seed` with the uniform evaluator,
+/// handing every position and the search made from it to `on_turn`.
+/// The record is left unfinished, the caller decides how the game ended
+pub fn play_with<F>(len: usize, seed: u64, play_stochastically: bool, mut on_turn: F) -> GameRecord
+where
+    F: FnMut(&GameState, &MCTSOutput),
+{
+    let mut game_state = GameState::init_game_state();
+    let mut tree_search = TreeSearch::with_seed(game_state.clone(), seed);
+    let mut record = GameRecord::new(
+        UniformEvaluator.model_id(),
+        SearchSettings::current(play_stochastically),
+        seed,
+    );
+    for _ in 0..len {
+        let output = tree_search.search(&UniformEvaluator, play_stochastically);
+        on_turn(&game_state, &output);
+        record.push_turn(&output);
+        game_state.move_game(output.best_move, None);
+    }
+    record
+}
+
+/// `play_with` for when only the record is needed
+pub fn play(len: usize, seed: u64, play_stochastically: bool) -> GameRecord {
+    play_with(len, seed, play_stochastically, |_, _| {})
+}
+
+/// A game that plays `moves` on the first row, where every search only visited the move played
+pub fn first_row_game(moves: &[usize], result: GameResult) -> GameRecord {
+    let mut record = GameRecord::new(
+        UniformEvaluator.model_id(),
+        SearchSettings::current(true),
+        0,
+    );
+    record.turns = moves
+        .iter()
+        .map(|&x| TurnRecord {
+            mv: Move::new(x, 0),
+            root_value: 0.0,
+            visits: vec![[x, 0, 1]],
+            time_ms: 0,
+        })
+        .collect();
+    record.finish(result);
+    record
+}
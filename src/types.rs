@@ -1,103 +1,209 @@
-use std::path::PathBuf;
+use std::path::Path;
 
-use ndarray::{concatenate, Array1, Array3, Array4, Axis};
-use ndarray_npy::{read_npy, write_npy, WriteNpyError};
+use ndarray::{concatenate, s, Array1, Array4, Axis};
 
 use crate::{
+    compact::CompactGames,
     constants::{self, sizes},
-    rules::types::*,
+    game_record::GameRecord,
+    shards::{self, ShardError, ShardInfo, ShardReader},
 };
 
+/// Finished games waiting to be dumped, kept as their records: the positions are expanded from them
+/// (see `compact`) when needed, so they can't disagree with what is stored
+#[derive(Default)]
 pub struct TrainingData {
-    num_turns: usize,
-    game_state_data: Vec<bool>,
-    pi_data: Vec<f32>,
-    outcomes: Option<Vec<f32>>,
-    sides: Vec<Side>,
+    records: Vec<GameRecord>,
 }
 
 impl TrainingData {
     pub fn new() -> Self {
-        Self {
-            num_turns: 0,
-            game_state_data: Vec::new(),
-            pi_data: Vec::new(),
-            outcomes: None,
-            sides: Vec::new(),
-        }
+        Self::default()
     }
-    pub fn append_turn(&mut self, gs: &GameState, p: &Array3<f32>) {
-        self.num_turns += 1;
-        self.sides.push(gs.get_side());
-        self.game_state_data
-            .extend(gs.get_contents_clone().into_iter());
-        self.pi_data.extend(p.iter());
-    }
-    pub fn set_result(&mut self, result: GameResult) {
-        self.outcomes = Some(
-            self.sides
-                .iter()
-                .map(|side| result.outcome_for_side(*side))
-                .collect(),
-        );
+    /// Adds the finished games of `game`, so they are dumped together
+    pub fn append_game(&mut self, game: TrainingData) {
+        self.records.extend(game.records);
     }
-    pub fn dump(self) -> Result<(), WriteNpyError> {
-        let game_state_data_path: PathBuf = [constants::TRAINING_DATA_PATH, "game_state_data.npy"]
-            .iter()
-            .collect();
-        let pi_data_path: PathBuf = [constants::TRAINING_DATA_PATH, "pi_data.npy"]
-            .iter()
-            .collect();
-        let result_data_path: PathBuf = [constants::TRAINING_DATA_PATH, "result_data.npy"]
+    /// Adds the record of a finished game
+    pub fn add_record(&mut self, record: GameRecord) {
+        self.records.push(record);
+    }
+    pub fn num_turns(&self) -> usize {
+        self.records.iter().map(|record| record.turns.len()).sum()
+    }
+    pub fn into_replay_data(self) -> Result<ReplayData, ShardError> {
+        Ok(self.into_parts()?.0)
+    }
+    /// The positions, and the records of the games they come from
+    pub fn into_parts(self) -> Result<(ReplayData, Vec<GameRecord>), ShardError> {
+        shards::check_finished(&self.records)?;
+        let data = CompactGames::encode(&self.records)
+            .decode()
+            .map_err(ShardError::Corrupt)?;
+        Ok((data, self.records))
+    }
+    /// Writes the games to a new shard in `constants::TRAINING_DATA_PATH`
+    pub fn dump(self) -> Result<ShardInfo, ShardError> {
+        shards::write_shard(Path::new(constants::TRAINING_DATA_PATH), &self.records)
+    }
+}
+
+/// The positions dumped by `TrainingData`, read back for training
+pub struct ReplayData {
+    pub game_states: Array4<bool>,
+    pub pis: Array4<f32>,
+    pub outcomes: Array1<f32>,
+    /// Value of the root of the search, the outcome for data written before it was recorded
+    pub root_values: Array1<f32>,
+}
+
+impl ReplayData {
+    /// Reads all the shards in the data directory `dir` (normally `constants::TRAINING_DATA_PATH`)
+    pub fn load<P: AsRef<Path>>(dir: P) -> Result<Self, ShardError> {
+        Self::concatenate(ShardReader::new(dir.as_ref())?.collect::<Result<_, _>>()?)
+    }
+
+    /// Reads the `max_len` most recent positions in the data directory `dir`,
+    /// without opening the shards that are too old
+    pub fn load_latest<P: AsRef<Path>>(dir: P, max_len: usize) -> Result<Self, ShardError> {
+        let dir = dir.as_ref();
+        let mut shards = shards::read_index(dir)?;
+        let mut len = 0;
+        let first = shards
             .iter()
-            .collect();
-        let game_state_data_path = game_state_data_path.to_str().unwrap();
-        let pi_data_path = pi_data_path.to_str().unwrap();
-        let result_data_path = result_data_path.to_str().unwrap();
-
-        {
-            let old_game_state_data: Array4<bool> =
-                read_npy(game_state_data_path).expect("Can't read previous game data");
-            let new_game_state_data = Array4::from_shape_vec(
-                (
-                    self.num_turns,
-                    sizes::GAME_STATE_HEIGHT,
-                    sizes::GAME_STATE_WIDTH,
-                    sizes::GAME_STATE_PLANES,
+            .rposition(|shard| {
+                len += shard.len;
+                len >= max_len
+            })
+            .unwrap_or(0);
+        let shards = shards.split_off(first);
+        let data =
+            Self::concatenate(ShardReader::from_shards(dir, shards).collect::<Result<_, _>>()?)?;
+        Ok(data.keep_latest(max_len))
+    }
+
+    /// Joins `parts` into one, in order
+    pub fn concatenate(parts: Vec<ReplayData>) -> Result<Self, ShardError> {
+        let corrupt = |e: ndarray::ShapeError| ShardError::Corrupt(e.to_string());
+        if parts.is_empty() {
+            return Ok(Self {
+                game_states: Array4::from_elem(
+                    (
+                        0,
+                        sizes::GAME_STATE_HEIGHT,
+                        sizes::GAME_STATE_WIDTH,
+                        sizes::GAME_STATE_PLANES,
+                    ),
+                    false,
                 ),
-                self.game_state_data,
-            )
-            .unwrap();
-            let game_state_data = concatenate![Axis(0), old_game_state_data, new_game_state_data];
-            write_npy(game_state_data_path, &game_state_data)?;
+                pis: Array4::zeros((0, sizes::MOVE_WIDTH, sizes::MOVE_HEIGHT, sizes::MOVE_PLANES)),
+                outcomes: Array1::zeros(0),
+                root_values: Array1::zeros(0),
+            });
         }
-        {
-            let old_pi_data: Array4<f32> =
-                read_npy(pi_data_path).expect("Can't read previous pi data");
-            let new_pi_data = Array4::from_shape_vec(
-                (
-                    self.num_turns,
-                    sizes::MOVE_WIDTH,
-                    sizes::MOVE_HEIGHT,
-                    sizes::MOVE_PLANES,
-                ),
-                self.pi_data,
-            )
-            .unwrap();
-            let pi_data = concatenate![Axis(0), old_pi_data, new_pi_data];
-            write_npy(pi_data_path, &pi_data)?;
+        let game_states: Vec<_> = parts.iter().map(|part| part.game_states.view()).collect();
+        let pis: Vec<_> = parts.iter().map(|part| part.pis.view()).collect();
+        let outcomes: Vec<_> = parts.iter().map(|part| part.outcomes.view()).collect();
+        let root_values: Vec<_> = parts.iter().map(|part| part.root_values.view()).collect();
+        Ok(Self {
+            game_states: concatenate(Axis(0), &game_states).map_err(corrupt)?,
+            pis: concatenate(Axis(0), &pis).map_err(corrupt)?,
+            outcomes: concatenate(Axis(0), &outcomes).map_err(corrupt)?,
+            root_values: concatenate(Axis(0), &root_values).map_err(corrupt)?,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.outcomes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.outcomes.is_empty()
+    }
+
+    /// Drops all but the `max_len` most recent positions
+    pub fn keep_latest(self, max_len: usize) -> Self {
+        let start = self.len().saturating_sub(max_len);
+        Self {
+            game_states: self.game_states.slice(s![start.., .., .., ..]).to_owned(),
+            pis: self.pis.slice(s![start.., .., .., ..]).to_owned(),
+            outcomes: self.outcomes.slice(s![start..]).to_owned(),
+            root_values: self.root_values.slice(s![start..]).to_owned(),
         }
-        {
-            let old_result_data: Array1<f32> =
-                read_npy(result_data_path).expect("Can't read previous result data");
-            let new_result_data = Array1::from(
-                self.outcomes
-                    .expect("Can't dump because result was not set"),
-            );
-            let result_data = concatenate![Axis(0), old_result_data, new_result_data];
-            write_npy(result_data_path, &result_data)?;
+    }
+
+    /// The positions `indices`, in that order
+    pub fn select(&self, indices: &[usize]) -> Self {
+        Self {
+            game_states: self.game_states.select(Axis(0), indices),
+            pis: self.pis.select(Axis(0), indices),
+            outcomes: self.outcomes.select(Axis(0), indices),
+            root_values: self.root_values.select(Axis(0), indices),
+        }
+    }
+
+    /// Value the network learns for position `i`: the outcome blended with the root value,
+    /// `q_weight` of 0 is only the outcome and 1 only the root value
+    pub fn value_target(&self, i: usize, q_weight: f32) -> f32 {
+        (1.0 - q_weight) * self.outcomes[i] + q_weight * self.root_values[i]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rules::types::GameResult;
+    use crate::test_util::{first_row_game, TempDir};
+
+    /// A game of `len` moves on the first row, where the root value of move `i` is `i / 10`
+    fn game(len: usize, result: GameResult) -> TrainingData {
+        let mut record = first_row_game(&(0..len).collect::<Vec<_>>(), result);
+        for (x, turn) in record.turns.iter_mut().enumerate() {
+            turn.root_value = x as f32 / 10.0;
         }
+        let mut game = TrainingData::new();
+        game.add_record(record);
+        game
+    }
+
+    #[test]
+    fn replay_data_test() {
+        let dir = TempDir::new("replay");
+        let mut data = TrainingData::new();
+        data.append_game(game(3, GameResult::XWins));
+        data.append_game(game(2, GameResult::Draws));
+        assert_eq!(data.num_turns(), 5);
+        shards::write_dense_shard(&dir, &data.into_replay_data().unwrap()).unwrap();
+        shards::write_dense_shard(
+            &dir,
+            &game(4, GameResult::OWins).into_replay_data().unwrap(),
+        )
+        .unwrap();
+
+        let data = ReplayData::load(&dir).unwrap();
+        assert_eq!(data.len(), 9);
+        assert_eq!(data.pis[[4, 0, 1, 0]], 1.0);
+        assert_eq!(data.outcomes[4], 0.0);
+        assert_eq!(data.root_values[4], 0.1);
+        assert_eq!(data.value_target(4, 0.0), 0.0);
+        assert_eq!(data.value_target(4, 1.0), 0.1);
+        assert_eq!(data.value_target(4, 0.5), 0.05);
+
+        // only the newest shard is needed
+        let latest = ReplayData::load_latest(&dir, 3).unwrap();
+        assert_eq!(latest.len(), 3);
+        assert_eq!(
+            latest.game_states,
+            data.game_states.slice(s![6.., .., .., ..])
+        );
+        assert_eq!(ReplayData::load_latest(&dir, 100).unwrap().len(), 9);
 
-        Ok(())
+        // a game without its result can't be stored
+        let mut unfinished = game(2, GameResult::Draws);
+        unfinished.records[0].result = GameResult::NotFinished;
+        assert!(matches!(
+            unfinished.into_replay_data(),
+            Err(ShardError::Unfinished(_))
+        ));
     }
 }
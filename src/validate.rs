@@ -0,0 +1,382 @@
+//! Checks of every position stored in the training data (`cargo run -- validate`).
+//! Each position is checked on its own: its planes, pi and outcome have to describe a legal, unfinished
+//! position, and when its game was recorded, it also has to be the position the recorded moves lead to
+use std::collections::BTreeMap;
+use std::fmt::{self, Display, Formatter};
+use std::path::Path;
+
+use ndarray::{s, ArrayView2, ArrayView3, Axis, Zip};
+
+use crate::constants::sizes;
+use crate::game_record::GameRecord;
+use crate::rules::types::{GameResult, GameState, Side};
+use crate::shards::{self, ShardError};
+use crate::types::ReplayData;
+
+// how far the sum of a pi can be from 1
+const PI_SUM_TOLERANCE: f32 = 1e-3;
+
+/// Something wrong with a stored position
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Problem {
+    /// The side to move plane isn't all the same
+    SidePlane,
+    /// A square of one of the boards has both an X and an O
+    Overlap,
+    /// The number of stones doesn't match the side to move
+    StoneCount,
+    /// The previous boards don't lead to the current one, one move at a time
+    History,
+    /// The game was already over
+    Terminal,
+    /// Pi has negative or non finite values
+    PiInvalid,
+    PiSum,
+    /// Pi plays on an occupied square
+    PiOnOccupied,
+    /// The outcome isn't -1, 0 or 1
+    Outcome,
+    /// The root value isn't between -1 and 1
+    RootValue,
+    /// The recorded moves of the game don't lead to the position
+    Replay,
+    /// The outcome doesn't match the recorded result of the game for the side to move
+    OutcomeForSide,
+    /// The games recorded for the shard don't add up to its positions
+    Records,
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let description = match self {
+            Problem::SidePlane => "the side to move plane isn't uniform",
+            Problem::Overlap => "a square has both an X and an O",
+            Problem::StoneCount => "the number of stones doesn't match the side to move",
+            Problem::History => "the previous boards don't lead to the current one",
+            Problem::Terminal => "the game was already over",
+            Problem::PiInvalid => "pi has negative or non finite values",
+            Problem::PiSum => "pi doesn't sum to 1",
+            Problem::PiOnOccupied => "pi plays on an occupied square",
+            Problem::Outcome => "the outcome isn't -1, 0 or 1",
+            Problem::RootValue => "the root value isn't between -1 and 1",
+            Problem::Replay => "the recorded moves don't lead to the position",
+            Problem::OutcomeForSide => "the outcome doesn't match the recorded result",
+            Problem::Records => "the recorded games don't add up to the positions",
+        };
+        write!(f, "{}", description)
+    }
+}
+
+/// Problems of the position `i` of `data`
+pub fn check_position(data: &ReplayData, i: usize) -> Vec<Problem> {
+    let game_state = data.game_states.index_axis(Axis(0), i);
+    let pi = data.pis.index_axis(Axis(0), i);
+    let mut problems = check_planes(game_state);
+
+    let board = game_state.slice(s![
+        ..,
+        ..,
+        sizes::BOARD_STATE_START..sizes::PLAYER_TO_MOVE_INDEX_IN_STATE
+    ]);
+    let occupied = Zip::from(board.index_axis(Axis(2), 0))
+        .and(board.index_axis(Axis(2), 1))
+        .map_collect(|&x, &o| x || o);
+    if pi.iter().any(|p| !p.is_finite() || *p < 0.0) {
+        problems.push(Problem::PiInvalid);
+    } else if (pi.sum() - 1.0).abs() > PI_SUM_TOLERANCE {
+        problems.push(Problem::PiSum);
+    }
+    if Zip::from(pi.index_axis(Axis(2), 0))
+        .and(&occupied)
+        .fold(false, |found, &p, &occupied| found || (occupied && p > 0.0))
+    {
+        problems.push(Problem::PiOnOccupied);
+    }
+    if ![-1.0, 0.0, 1.0].contains(&data.outcomes[i]) {
+        problems.push(Problem::Outcome);
+    }
+    let root_value = data.root_values[i];
+    if !root_value.is_finite() || root_value.abs() > 1.0 {
+        problems.push(Problem::RootValue);
+    }
+    problems
+}
+
+/// Problems of the planes of a game state
+fn check_planes(game_state: ArrayView3<bool>) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    let side_plane = game_state.index_axis(Axis(2), sizes::PLAYER_TO_MOVE_INDEX_IN_STATE);
+    if side_plane.iter().any(|&b| b != side_plane[[0, 0]]) {
+        problems.push(Problem::SidePlane);
+    }
+    let side = if side_plane[[0, 0]] { Side::O } else { Side::X };
+    // the boards from the oldest to the current one, as (X plane, O plane)
+    let boards: Vec<(ArrayView2<bool>, ArrayView2<bool>)> = (0..=sizes::NUM_PREV_BOARDS)
+        .map(|b| {
+            let plane = b * sizes::PLANES_PER_PREV_BOARD;
+            (
+                game_state.index_axis(Axis(2), plane),
+                game_state.index_axis(Axis(2), plane + 1),
+            )
+        })
+        .collect();
+    if boards.iter().any(|(x, o)| {
+        Zip::from(x)
+            .and(o)
+            .fold(false, |found, &x, &o| found || (x && o))
+    }) {
+        problems.push(Problem::Overlap);
+    }
+
+    let count = |plane: &ArrayView2<bool>| plane.iter().filter(|&&b| b).count();
+    let (x, o) = &boards[sizes::NUM_PREV_BOARDS];
+    let x_ahead = match side {
+        Side::X => 0,
+        Side::O => 1,
+    };
+    if count(x) != count(o) + x_ahead {
+        problems.push(Problem::StoneCount);
+    }
+
+    // every board is the one before with a stone of the side that moved, unless both are before the game
+    for (newer, pair) in boards.windows(2).enumerate().map(|(b, pair)| (b + 1, pair)) {
+        let ((old_x, old_o), (new_x, new_o)) = (&pair[0], &pair[1]);
+        let moves_ago = sizes::NUM_PREV_BOARDS - newer;
+        let mover = if moves_ago.is_multiple_of(2) {
+            side.opponent()
+        } else {
+            side
+        };
+        let removed = |old: &ArrayView2<bool>, new: &ArrayView2<bool>| {
+            Zip::from(old)
+                .and(new)
+                .fold(false, |found, &old, &new| found || (old && !new))
+        };
+        let added = (
+            count(new_x).saturating_sub(count(old_x)),
+            count(new_o).saturating_sub(count(old_o)),
+        );
+        let expected = match mover {
+            Side::X => (1, 0),
+            Side::O => (0, 1),
+        };
+        let before_game = count(new_x) + count(new_o) == 0;
+        if removed(old_x, new_x) || removed(old_o, new_o) || !(before_game || added == expected) {
+            problems.push(Problem::History);
+            break;
+        }
+    }
+
+    let game_state = GameState::from_contents(game_state.to_owned());
+    if game_state.evaluate() != GameResult::NotFinished {
+        problems.push(Problem::Terminal);
+    }
+    problems
+}
+
+/// Problems of the positions `start..` of `data`, that come from the game `record`
+pub fn check_game(data: &ReplayData, start: usize, record: &GameRecord) -> Vec<(usize, Problem)> {
+    let mut problems = Vec::new();
+    for (i, position) in record.positions().iter().enumerate() {
+        let stored = data.game_states.index_axis(Axis(0), start + i);
+        if stored != position.get_contents_clone() {
+            problems.push((start + i, Problem::Replay));
+        }
+        if data.outcomes[start + i] != record.result.outcome_for_side(position.get_side())
+            || record.result == GameResult::NotFinished
+        {
+            problems.push((start + i, Problem::OutcomeForSide));
+        }
+    }
+    problems
+}
+
+/// How often a problem was found
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ProblemCount {
+    pub count: usize,
+    /// The shard and position where it was first found
+    pub first: (String, usize),
+}
+
+/// What `validate` found
+#[derive(Default, PartialEq, Debug)]
+pub struct Report {
+    pub shards: usize,
+    pub positions: usize,
+    pub problems: BTreeMap<Problem, ProblemCount>,
+    /// Shards that couldn't be read, with the error
+    pub unreadable: Vec<(String, String)>,
+}
+
+impl Report {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty() && self.unreadable.is_empty()
+    }
+
+    fn add(&mut self, shard: &str, position: usize, problem: Problem) {
+        self.problems
+            .entry(problem)
+            .and_modify(|problem| problem.count += 1)
+            .or_insert_with(|| ProblemCount {
+                count: 1,
+                first: (shard.to_string(), position),
+            });
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Checked {} positions in {} shards",
+            self.positions, self.shards
+        )?;
+        for (shard, error) in &self.unreadable {
+            writeln!(f, "{}: can't be read: {}", shard, error)?;
+        }
+        for (problem, count) in &self.problems {
+            writeln!(
+                f,
+                "{} positions: {}, first in {} at position {}",
+                count.count, problem, count.first.0, count.first.1
+            )?;
+        }
+        if self.is_ok() {
+            writeln!(f, "No problems found")?;
+        }
+        Ok(())
+    }
+}
+
+/// Checks every position of the data directory `dir`
+pub fn validate(dir: &Path) -> Result<Report, ShardError> {
+    let mut report = Report::default();
+    for shard in shards::read_index(dir)? {
+        report.shards += 1;
+        let read = shards::read_shard(dir, &shard)
+            .and_then(|data| Ok((data, shards::read_records(dir, &shard)?)));
+        let (data, records) = match read {
+            Ok(read) => read,
+            Err(e) => {
+                report.unreadable.push((shard.file.clone(), e.to_string()));
+                continue;
+            }
+        };
+        report.positions += data.len();
+        for i in 0..data.len() {
+            for problem in check_position(&data, i) {
+                report.add(&shard.file, i, problem);
+            }
+        }
+        if records.is_empty() {
+            continue;
+        }
+        if records
+            .iter()
+            .map(|record| record.turns.len())
+            .sum::<usize>()
+            != data.len()
+        {
+            report.add(&shard.file, 0, Problem::Records);
+            continue;
+        }
+        let mut start = 0;
+        for record in &records {
+            for (i, problem) in check_game(&data, start, record) {
+                report.add(&shard.file, i, problem);
+            }
+            start += record.turns.len();
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rules::types::Move;
+    use crate::test_util::{self, TempDir};
+    use crate::types::TrainingData;
+
+    /// The first `len` moves of a seeded game, both as positions and as its record
+    fn play(len: usize, seed: u64) -> (ReplayData, GameRecord) {
+        let mut record = test_util::play(len, seed, false);
+        record.finish(GameResult::OWins);
+        let mut data = TrainingData::new();
+        data.add_record(record.clone());
+        (data.into_replay_data().unwrap(), record)
+    }
+
+    #[test]
+    fn check_position_test() {
+        let (data, record) = play(4, 5);
+        for i in 0..data.len() {
+            assert_eq!(check_position(&data, i), vec![]);
+        }
+        assert_eq!(check_game(&data, 0, &record), vec![]);
+
+        let mut broken = data.select(&[0, 1, 2, 3]);
+        // pi on the stone of the first move
+        let first = record.turns[0].mv;
+        broken.pis[[1, first.y, first.x, 0]] = 0.5;
+        broken.outcomes[2] = 0.5;
+        // an O appears out of nowhere, where the search didn't play
+        let free = (0..13 * 13)
+            .map(|i| Move::new(i % 13, i / 13))
+            .find(|mv| {
+                !data.game_states[[3, mv.y, mv.x, 6]]
+                    && !data.game_states[[3, mv.y, mv.x, 7]]
+                    && data.pis[[3, mv.y, mv.x, 0]] == 0.0
+            })
+            .unwrap();
+        broken.game_states[[3, free.y, free.x, 7]] = true;
+        assert_eq!(check_position(&broken, 0), vec![]);
+        assert_eq!(
+            check_position(&broken, 1),
+            vec![Problem::PiSum, Problem::PiOnOccupied]
+        );
+        assert_eq!(check_position(&broken, 2), vec![Problem::Outcome]);
+        assert_eq!(
+            check_position(&broken, 3),
+            vec![Problem::StoneCount, Problem::History]
+        );
+        assert_eq!(
+            check_game(&broken, 0, &record),
+            vec![(2, Problem::OutcomeForSide), (3, Problem::Replay)]
+        );
+
+        // a won position isn't training data
+        let mut game_state = GameState::init_game_state();
+        for x in 0..4 {
+            game_state.move_game(Move::new(x, 0), None);
+            game_state.move_game(Move::new(x, 1), None);
+        }
+        game_state.move_game(Move::new(4, 0), None);
+        assert!(check_planes(game_state.get_contents_clone().view()).contains(&Problem::Terminal));
+    }
+
+    #[test]
+    fn validate_test() {
+        let dir = TempDir::new("validate");
+        let (_, record) = play(3, 1);
+        shards::write_shard(&dir, &[record]).unwrap();
+        let (mut data, _) = play(2, 2);
+        data.root_values[1] = 2.0;
+        shards::write_dense_shard(&dir, &data).unwrap();
+
+        let report = validate(&dir).unwrap();
+        assert_eq!((report.shards, report.positions), (2, 5));
+        assert!(!report.is_ok());
+        assert_eq!(
+            report.problems,
+            BTreeMap::from([(
+                Problem::RootValue,
+                ProblemCount {
+                    count: 1,
+                    first: ("shard-000001.npz".into(), 1)
+                }
+            )])
+        );
+    }
+}